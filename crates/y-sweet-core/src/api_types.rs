@@ -1,6 +1,9 @@
+use anyhow::{bail, Context, Result};
+use data_encoding::BASE64;
 use serde::{Deserialize, Serialize};
+use yrs::{updates::decoder::Decode, Any, Array, Map, Text, Transact, Update, WriteTxn};
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug)]
 pub struct NewDocResponse {
     #[serde(rename = "docId")]
     pub doc_id: String,
@@ -28,6 +31,10 @@ pub struct AuthDocRequest {
     pub user_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", rename = "validForSeconds")]
     pub valid_for_seconds: Option<u64>,
+    /// Issue a token for every doc whose ID matches this pattern instead of only the
+    /// requested doc, e.g. `ws123-*`. The pattern must match the requested doc.
+    #[serde(rename = "docPattern")]
+    pub doc_pattern: Option<String>,
 }
 
 impl Default for AuthDocRequest {
@@ -36,6 +43,7 @@ impl Default for AuthDocRequest {
             authorization: Authorization::Full,
             user_id: None,
             valid_for_seconds: None,
+            doc_pattern: None,
         }
     }
 }
@@ -63,11 +71,162 @@ pub struct ClientToken {
     pub authorization: Authorization,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct DocCreationRequest {
     /// The ID of the document to create. If not provided, a random ID will be generated.
     #[serde(skip_serializing_if = "Option::is_none", rename = "docId")]
     pub doc_id: Option<String>,
+    /// A Yjs v1 update to initialize the document with, base64-encoded.
+    #[serde(rename = "initialUpdate")]
+    pub initial_update: Option<String>,
+    /// A JSON object to initialize the document with. Each entry becomes a root type:
+    /// strings become texts, arrays become arrays and objects become maps. Values inside
+    /// those are stored as plain values rather than as nested shared types.
+    #[serde(rename = "initialContent")]
+    pub initial_content: Option<serde_json::Value>,
+}
+
+impl DocCreationRequest {
+    /// The initial state of the document as a Yjs v1 update, if the request sets one.
+    pub fn initial_state(&self) -> Result<Option<Vec<u8>>> {
+        match (&self.initial_update, &self.initial_content) {
+            (Some(_), Some(_)) => bail!("Only one of initialUpdate and initialContent can be set."),
+            (Some(update), None) => {
+                let update = BASE64
+                    .decode(update.as_bytes())
+                    .context("initialUpdate is not valid base64")?;
+                Update::decode_v1(&update).context("initialUpdate is not a valid Yjs update")?;
+                Ok(Some(update))
+            }
+            (None, Some(content)) => json_to_update(content).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+fn json_to_update(content: &serde_json::Value) -> Result<Vec<u8>> {
+    let serde_json::Value::Object(roots) = content else {
+        bail!("initialContent must be a JSON object.");
+    };
+
+    let doc = yrs::Doc::new();
+    let mut txn = doc.transact_mut();
+    for (name, value) in roots {
+        match value {
+            serde_json::Value::String(text) => {
+                txn.get_or_insert_text(name.as_str())
+                    .insert(&mut txn, 0, text);
+            }
+            serde_json::Value::Array(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| serde_json::from_value::<Any>(item.clone()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                txn.get_or_insert_array(name.as_str())
+                    .insert_range(&mut txn, 0, items);
+            }
+            serde_json::Value::Object(entries) => {
+                let map = txn.get_or_insert_map(name.as_str());
+                for (key, value) in entries {
+                    let value = serde_json::from_value::<Any>(value.clone())?;
+                    map.insert(&mut txn, key.as_str(), value);
+                }
+            }
+            _ => bail!(
+                "Entry {:?} of initialContent must be a string, array or object.",
+                name
+            ),
+        }
+    }
+    Ok(txn.encode_update_v1())
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DocForkRequest {
+    /// The ID of the new document. If not provided, a random ID will be generated.
+    #[serde(rename = "docId")]
+    pub doc_id: Option<String>,
+    /// A Yjs snapshot of the source document, v1-encoded and then base64-encoded. If
+    /// provided, the new document starts from the source's state at the snapshot
+    /// instead of its current state.
+    pub snapshot: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DocStats {
+    /// Size in bytes of the document state, encoded as a Yjs v1 update.
+    pub encoded_size: usize,
+    /// Number of entries in the document's key-value store.
+    pub sync_kv_entries: usize,
+    /// Number of updates that have not yet been merged into the document state.
+    pub pending_updates: usize,
+    /// Time of the last successful persist, in milliseconds since epoch.
+    pub last_persisted_millis: Option<u64>,
+    /// Whether the document has changes that have not been persisted.
+    pub dirty: bool,
+    /// Number of open connections to the document.
+    pub connections: usize,
+    /// Number of clients with an awareness state.
+    pub awareness_clients: usize,
+    /// Number of distinct Yjs client IDs in the document's state vector.
+    pub state_vector_clients: usize,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CompactDocRequest {
+    /// Report the size the document would have after compaction without writing it.
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CompactionReport {
+    /// Size in bytes of the document's key-value store before compaction.
+    pub size_before: usize,
+    /// Size in bytes of the document's key-value store after compaction.
+    pub size_after: usize,
+    /// Whether the compacted document was only measured and not written.
+    pub dry_run: bool,
+}
+
+/// Metadata about a document that is kept outside of the document itself, so that
+/// only holders of the server token can change it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DocMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Time the document was created, in milliseconds since epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Any other fields, which are stored as given.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DocListing {
+    pub doc_id: String,
+    /// The document's metadata, if any has been set.
+    pub metadata: Option<DocMetadata>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListDocsResponse {
+    pub docs: Vec<DocListing>,
+    /// Where the next page of documents starts, if there may be more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
 }
 
 /// Validate that the document name contains only alphanumeric characters, dashes, and underscores.
@@ -83,3 +242,84 @@ pub fn validate_doc_name(doc_name: &str) -> bool {
     }
     true
 }
+
+/// Whether `pattern` is a valid doc pattern: a doc name in which `*` may also stand
+/// for any run of characters.
+pub fn validate_doc_pattern(pattern: &str) -> bool {
+    !pattern.is_empty()
+        && pattern
+            .split('*')
+            .all(|part| part.is_empty() || validate_doc_name(part))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use yrs::{types::ToJson, GetString, ReadTxn};
+
+    fn request(body: serde_json::Value) -> DocCreationRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn initial_state_from_json() {
+        let content = json!({
+            "text": "hello",
+            "list": [1, "two", {"three": 3}],
+            "map": {"key": "value", "nested": [true]},
+        });
+        let update = request(json!({ "initialContent": content }))
+            .initial_state()
+            .unwrap()
+            .unwrap();
+
+        let doc = yrs::Doc::new();
+        let (text, list, map) = (
+            doc.get_or_insert_text("text"),
+            doc.get_or_insert_array("list"),
+            doc.get_or_insert_map("map"),
+        );
+        doc.transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+
+        let txn = doc.transact();
+        assert_eq!(text.get_string(&txn), "hello");
+        let list = serde_json::to_value(list.to_json(&txn)).unwrap();
+        assert_eq!(list, json!([1, "two", {"three": 3}]));
+        let map = serde_json::to_value(map.to_json(&txn)).unwrap();
+        assert_eq!(map, json!({"key": "value", "nested": [true]}));
+        assert_eq!(txn.root_refs().count(), 3);
+    }
+
+    #[test]
+    fn initial_state_from_update() {
+        let doc = yrs::Doc::new();
+        let update = {
+            let mut txn = doc.transact_mut();
+            txn.get_or_insert_text("text").insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+
+        let body = json!({ "initialUpdate": BASE64.encode(&update) });
+        assert_eq!(request(body).initial_state().unwrap(), Some(update));
+
+        assert_eq!(request(json!({})).initial_state().unwrap(), None);
+        assert!(request(json!({ "initialUpdate": "not base64!" }))
+            .initial_state()
+            .is_err());
+        assert!(
+            request(json!({ "initialContent": ["not", "an", "object"] }))
+                .initial_state()
+                .is_err()
+        );
+        assert!(request(json!({ "initialContent": { "number": 1 } }))
+            .initial_state()
+            .is_err());
+        assert!(
+            request(json!({ "initialUpdate": "", "initialContent": {} }))
+                .initial_state()
+                .is_err()
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::api_types::Authorization;
+use crate::api_types::{validate_doc_name, Authorization};
 use bincode::Options;
 use data_encoding::Encoding;
 use rand::Rng;
@@ -97,10 +97,62 @@ pub struct DocPermission {
     pub authorization: Authorization,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct UserDocPermission {
+    pub doc_id: String,
+    pub authorization: Authorization,
+    pub user_id: String,
+}
+
+/// Gives access to every doc whose ID matches `pattern`, in which `*` stands for any
+/// run of characters, e.g. `ws123-*`.
+#[derive(Serialize, Deserialize)]
+pub struct DocPatternPermission {
+    pub pattern: String,
+    pub authorization: Authorization,
+    pub user_id: Option<String>,
+}
+
+impl DocPatternPermission {
+    pub fn matches(&self, doc_id: &str) -> bool {
+        doc_pattern_matches(&self.pattern, doc_id)
+    }
+}
+
+/// Whether `doc_id` matches `pattern`, in which `*` stands for any run of characters,
+/// including none. Doc IDs cannot contain `*`, so it never has to be escaped. Only
+/// valid doc names match, so that `*` cannot stand for a `/` or `..` that would reach
+/// a key outside of the pattern's scope.
+pub fn doc_pattern_matches(pattern: &str, doc_id: &str) -> bool {
+    if !validate_doc_name(doc_id) {
+        return false;
+    }
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = doc_id.strip_prefix(first) else {
+        return false;
+    };
+    let Some(last) = parts.next_back() else {
+        // The pattern has no `*`, so it must match the doc ID exactly.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Bincode encodes enum variants by index, so new variants must be added at the end
+/// to keep previously-issued tokens valid.
 #[derive(Serialize, Deserialize)]
 pub enum Permission {
     Server,
     Doc(DocPermission),
+    UserDoc(UserDocPermission),
+    DocPattern(DocPatternPermission),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -320,6 +372,10 @@ impl Authenticator {
         b64_encode(&self.private_key)
     }
 
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
     pub fn gen_doc_token(
         &self,
         doc_id: &str,
@@ -336,6 +392,45 @@ impl Authenticator {
         self.sign(payload)
     }
 
+    /// Like `gen_doc_token`, but the token also records the ID of the user it was issued to.
+    pub fn gen_doc_token_for_user(
+        &self,
+        doc_id: &str,
+        authorization: Authorization,
+        user_id: &str,
+        expiration_time: ExpirationTimeEpochMillis,
+    ) -> String {
+        let payload = Payload::new_with_expiration(
+            Permission::UserDoc(UserDocPermission {
+                doc_id: doc_id.to_string(),
+                authorization,
+                user_id: user_id.to_string(),
+            }),
+            expiration_time,
+        );
+        self.sign(payload)
+    }
+
+    /// Like `gen_doc_token`, but the token gives access to every doc whose ID matches
+    /// `pattern` (see `doc_pattern_matches`), and optionally records a user ID.
+    pub fn gen_doc_pattern_token(
+        &self,
+        pattern: &str,
+        authorization: Authorization,
+        user_id: Option<&str>,
+        expiration_time: ExpirationTimeEpochMillis,
+    ) -> String {
+        let payload = Payload::new_with_expiration(
+            Permission::DocPattern(DocPatternPermission {
+                pattern: pattern.to_string(),
+                authorization,
+                user_id: user_id.map(str::to_owned),
+            }),
+            expiration_time,
+        );
+        self.sign(payload)
+    }
+
     fn verify_token(
         &self,
         token: &str,
@@ -351,17 +446,42 @@ impl Authenticator {
         doc: &str,
         current_time_epoch_millis: u64,
     ) -> Result<Authorization, AuthError> {
+        self.verify_doc_token_with_user_id(token, doc, current_time_epoch_millis)
+            .map(|(authorization, _)| authorization)
+    }
+
+    /// Verify a doc token, also returning the user ID it was issued to (if any).
+    pub fn verify_doc_token_with_user_id(
+        &self,
+        token: &str,
+        doc: &str,
+        current_time_epoch_millis: u64,
+    ) -> Result<(Authorization, Option<String>), AuthError> {
         let payload = self.verify_token(token, current_time_epoch_millis)?;
 
         match payload {
             Permission::Doc(doc_permission) => {
                 if doc_permission.doc_id == doc {
-                    Ok(doc_permission.authorization)
+                    Ok((doc_permission.authorization, None))
+                } else {
+                    Err(AuthError::InvalidResource)
+                }
+            }
+            Permission::UserDoc(doc_permission) => {
+                if doc_permission.doc_id == doc {
+                    Ok((doc_permission.authorization, Some(doc_permission.user_id)))
+                } else {
+                    Err(AuthError::InvalidResource)
+                }
+            }
+            Permission::DocPattern(doc_permission) => {
+                if doc_permission.matches(doc) {
+                    Ok((doc_permission.authorization, doc_permission.user_id))
                 } else {
                     Err(AuthError::InvalidResource)
                 }
             }
-            Permission::Server => Ok(Authorization::Full), // Server tokens can access any doc.
+            Permission::Server => Ok((Authorization::Full, None)), // Server tokens can access any doc.
         }
     }
 
@@ -432,6 +552,86 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_user_doc_token() {
+        let authenticator = Authenticator::gen_key().unwrap();
+        let token = authenticator.gen_doc_token_for_user(
+            "doc123",
+            Authorization::ReadOnly,
+            "user456",
+            ExpirationTimeEpochMillis(0),
+        );
+        assert!(matches!(
+            authenticator.verify_doc_token_with_user_id(&token, "doc123", 0),
+            Ok((Authorization::ReadOnly, Some(user_id))) if user_id == "user456"
+        ));
+        assert!(matches!(
+            authenticator.verify_doc_token(&token, "doc456", 0),
+            Err(AuthError::InvalidResource)
+        ));
+
+        let token = authenticator.gen_doc_token(
+            "doc123",
+            Authorization::Full,
+            ExpirationTimeEpochMillis(0),
+        );
+        assert!(matches!(
+            authenticator.verify_doc_token_with_user_id(&token, "doc123", 0),
+            Ok((Authorization::Full, None))
+        ));
+    }
+
+    #[test]
+    fn test_doc_pattern_matches() {
+        assert!(doc_pattern_matches("ws123-*", "ws123-"));
+        assert!(doc_pattern_matches("ws123-*", "ws123-doc1"));
+        assert!(!doc_pattern_matches("ws123-*", "ws1234-doc1"));
+        assert!(!doc_pattern_matches("ws123-*", "xws123-doc1"));
+        assert!(doc_pattern_matches("*-notes", "ws123-notes"));
+        assert!(!doc_pattern_matches("*-notes", "ws123-notes-old"));
+        assert!(doc_pattern_matches("ws*-doc*-v2", "ws1-doc7-v2"));
+        assert!(!doc_pattern_matches("ws*-doc*-v2", "ws1-v2"));
+        assert!(doc_pattern_matches("a*a", "aa"));
+        assert!(!doc_pattern_matches("a*a", "a"));
+        assert!(doc_pattern_matches("*", "anything"));
+        assert!(doc_pattern_matches("doc1", "doc1"));
+        assert!(!doc_pattern_matches("doc1", "doc12"));
+        assert!(!doc_pattern_matches("ws123-*", "ws123-x/../secret"));
+        assert!(!doc_pattern_matches("ws123-*", "ws123-x/doc1"));
+        assert!(!doc_pattern_matches("*", ".."));
+    }
+
+    #[test]
+    fn test_doc_pattern_token() {
+        let authenticator = Authenticator::gen_key().unwrap();
+        let token = authenticator.gen_doc_pattern_token(
+            "ws123-*",
+            Authorization::ReadOnly,
+            Some("user456"),
+            ExpirationTimeEpochMillis(0),
+        );
+        assert!(matches!(
+            authenticator.verify_doc_token_with_user_id(&token, "ws123-doc1", 0),
+            Ok((Authorization::ReadOnly, Some(user_id))) if user_id == "user456"
+        ));
+        assert!(matches!(
+            authenticator.verify_doc_token(&token, "ws123-doc2", 0),
+            Ok(Authorization::ReadOnly)
+        ));
+        assert!(matches!(
+            authenticator.verify_doc_token(&token, "ws124-doc1", 0),
+            Err(AuthError::InvalidResource)
+        ));
+        assert!(matches!(
+            authenticator.verify_doc_token(&token, "ws123-x/../secret", 0),
+            Err(AuthError::InvalidResource)
+        ));
+        assert!(matches!(
+            authenticator.verify_server_token(&token, 0),
+            Err(AuthError::InvalidResource)
+        ));
+    }
+
     #[test]
     fn test_server_token_for_doc_auth() {
         let authenticator = Authenticator::gen_key().unwrap();
@@ -0,0 +1,40 @@
+//! A hook for deciding who may open a doc, for deployments whose access rules live
+//! outside of y-sweet. When a server has an `Authorizer`, it is asked instead of
+//! verifying the token as a y-sweet doc token.
+
+use crate::api_types::Authorization;
+use async_trait::async_trait;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AuthorizerError {
+    /// The token does not give access to the doc.
+    #[error("Access denied. {0}")]
+    Denied(String),
+    /// No decision could be made, e.g. because the service that makes it could not be
+    /// reached.
+    #[error("Authorizer is unavailable. {0}")]
+    Unavailable(String),
+}
+
+#[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
+pub trait Authorizer {
+    /// Decides what the bearer of `token` may do with the doc `doc_id`.
+    async fn authorize(
+        &self,
+        doc_id: &str,
+        token: Option<&str>,
+    ) -> Result<Authorization, AuthorizerError>;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    /// Decides what the bearer of `token` may do with the doc `doc_id`.
+    async fn authorize(
+        &self,
+        doc_id: &str,
+        token: Option<&str>,
+    ) -> Result<Authorization, AuthorizerError>;
+}
@@ -1,16 +1,38 @@
-use crate::{doc_connection::DOC_NAME, store::Store, sync::awareness::Awareness, sync_kv::SyncKv};
+use crate::{
+    api_types::DocStats, doc_connection::DOC_NAME, snapshot::SnapshotFormat, store::Store,
+    sync::awareness::Awareness, sync_kv::SyncKv,
+};
 use anyhow::{anyhow, Context, Result};
-use std::sync::{Arc, RwLock};
-use yrs::{updates::decoder::Decode, ReadTxn, StateVector, Subscription, Transact, Update};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+};
+use yrs::{
+    updates::{decoder::Decode, encoder::Encoder, encoder::EncoderV1},
+    ReadTxn, Snapshot, StateVector, Subscription, Transact, Update,
+};
 use yrs_kvstore::DocOps;
 
 pub struct DocWithSyncKv {
     awareness: Arc<RwLock<Awareness>>,
     sync_kv: Arc<SyncKv>,
+    /// Number of open connections to the doc, see `open_connection`.
+    connections: Arc<AtomicUsize>,
     #[allow(unused)] // acts as RAII guard
     subscription: Subscription,
 }
 
+/// A connection to a doc, which is counted in the doc's stats until it is dropped.
+pub struct OpenConnection {
+    connections: Arc<AtomicUsize>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 impl DocWithSyncKv {
     pub fn awareness(&self) -> Arc<RwLock<Awareness>> {
         self.awareness.clone()
@@ -20,18 +42,28 @@ impl DocWithSyncKv {
         self.sync_kv.clone()
     }
 
+    /// Counts a connection to the doc until the returned guard is dropped.
+    pub fn open_connection(&self) -> OpenConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection {
+            connections: self.connections.clone(),
+        }
+    }
+
     pub async fn new<F>(
         key: &str,
         store: Option<Arc<Box<dyn Store>>>,
         dirty_callback: F,
         skip_gc: bool,
+        snapshot_format: SnapshotFormat,
     ) -> Result<Self>
     where
         F: Fn() + Send + Sync + 'static,
     {
         let sync_kv = SyncKv::new(store, key, dirty_callback)
             .await
-            .context("Failed to create SyncKv")?;
+            .context("Failed to create SyncKv")?
+            .with_format(snapshot_format);
 
         let sync_kv = Arc::new(sync_kv);
         let doc = yrs::Doc::with_options(yrs::Options {
@@ -58,9 +90,25 @@ impl DocWithSyncKv {
         };
 
         let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+
+        // A weak reference, since the doc's update observer holds on to the SyncKv.
+        let weak_awareness = Arc::downgrade(&awareness);
+        sync_kv.on_merge(Box::new(move |update| {
+            let Some(awareness) = weak_awareness.upgrade() else {
+                return false;
+            };
+            let Ok(update) = Update::decode_v1(update) else {
+                return false;
+            };
+            let awareness_guard = awareness.write().unwrap();
+            awareness_guard.doc.transact_mut().apply_update(update);
+            true
+        }));
+
         Ok(Self {
             awareness,
             sync_kv,
+            connections: Arc::default(),
             subscription,
         })
     }
@@ -74,6 +122,35 @@ impl DocWithSyncKv {
         txn.encode_state_as_update_v1(&StateVector::default())
     }
 
+    /// The state of the doc at `snapshot`, encoded as a v1 update. This needs the
+    /// content deleted since the snapshot, so it fails unless garbage collection is
+    /// disabled for the doc.
+    pub fn as_update_at(&self, snapshot: &Snapshot) -> Result<Vec<u8>> {
+        let awareness_guard = self.awareness.read().unwrap();
+        let txn = awareness_guard.doc.transact();
+
+        let mut encoder = EncoderV1::new();
+        txn.encode_state_from_snapshot(snapshot, &mut encoder)
+            .map_err(|e| anyhow!("Failed to encode doc at snapshot: {}", e))?;
+        Ok(encoder.to_vec())
+    }
+
+    pub fn stats(&self) -> DocStats {
+        let awareness_guard = self.awareness.read().unwrap();
+        let txn = awareness_guard.doc.transact();
+
+        DocStats {
+            encoded_size: txn.encode_state_as_update_v1(&StateVector::default()).len(),
+            sync_kv_entries: self.sync_kv.len(),
+            pending_updates: self.sync_kv.pending_update_count(),
+            last_persisted_millis: self.sync_kv.last_persisted_millis(),
+            dirty: self.sync_kv.is_dirty(),
+            connections: self.connections.load(Ordering::Relaxed),
+            awareness_clients: awareness_guard.clients().len(),
+            state_vector_clients: txn.state_vector().len(),
+        }
+    }
+
     pub fn apply_update(&self, update: &[u8]) -> Result<()> {
         let awareness_guard = self.awareness.write().unwrap();
         let doc = &awareness_guard.doc;
@@ -1,7 +1,10 @@
 pub mod api_types;
 pub mod auth;
+pub mod authorizer;
 pub mod doc_connection;
 pub mod doc_sync;
+pub mod snapshot;
 pub mod store;
 pub mod sync;
 pub mod sync_kv;
+pub mod tenant;
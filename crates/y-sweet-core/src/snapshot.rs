@@ -0,0 +1,375 @@
+//! Encoding of the snapshots that `SyncKv` persists to the store.
+//!
+//! The contents of a snapshot are the bincode encoding of the key-value map. With
+//! `SnapshotFormat::Container`, they are wrapped in a container that starts with a magic
+//! number and a format version:
+//!
+//! ```text
+//! version 2:
+//!   magic (4 bytes, "YSNP") | format version (1 byte) | checksum (32 bytes)
+//!   | compression (1 byte) | created at (8 bytes) | state vector length (4 bytes)
+//!   | state vector | payload
+//!
+//! version 1:
+//!   magic (4 bytes, "YSNP") | format version (1 byte) | compression (1 byte) | payload
+//! ```
+//!
+//! Integers are little-endian. The checksum is the SHA-256 of everything after it, the
+//! creation time is in milliseconds since the Unix epoch, and the state vector is the
+//! v1 encoding of the doc's state vector at the time of the snapshot. Version 2 is
+//! written; version 1 is still read.
+//!
+//! With `SnapshotFormat::Legacy`, the default, snapshots are plain bincode, which is all
+//! that versions before the container can read. Bincode starts the map with its length
+//! as a `u64`, and the magic read as such a length would exceed any real snapshot, so
+//! both formats can be told apart and read.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fmt::Display, io::Read, str::FromStr};
+use thiserror::Error;
+
+pub type SnapshotData = BTreeMap<Vec<u8>, Vec<u8>>;
+
+const MAGIC: &[u8; 4] = b"YSNP";
+const FORMAT_VERSION: u8 = 2;
+const CHECKSUM_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Unsupported snapshot format version {0}.")]
+    UnsupportedVersion(u8),
+    #[error("Unknown snapshot compression {0}.")]
+    UnknownCompression(u8),
+    #[error("Snapshot is truncated.")]
+    Truncated,
+    #[error("Snapshot checksum does not match its contents; the snapshot is corrupt.")]
+    ChecksumMismatch,
+    #[error("Failed to decompress snapshot: {0}")]
+    Decompression(String),
+    #[error("Failed to decode snapshot: {0}")]
+    Decode(#[from] bincode::Error),
+}
+
+/// How snapshots are compressed when they are written. Snapshots are always
+/// readable regardless of this setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, SnapshotError> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            _ => Err(SnapshotError::UnknownCompression(id)),
+        }
+    }
+
+    fn compress(self, payload: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => payload,
+            Compression::Zstd => ruzstd::encoding::compress_to_vec(
+                payload.as_slice(),
+                ruzstd::encoding::CompressionLevel::Fastest,
+            ),
+        }
+    }
+
+    fn decompress(self, mut payload: &[u8]) -> Result<Vec<u8>, SnapshotError> {
+        match self {
+            Compression::None => Ok(payload.to_vec()),
+            Compression::Zstd => {
+                let mut decoder = ruzstd::decoding::StreamingDecoder::new(&mut payload)
+                    .map_err(|e| SnapshotError::Decompression(e.to_string()))?;
+                let mut decompressed = Vec::new();
+                decoder
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| SnapshotError::Decompression(e.to_string()))?;
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "Unknown compression {s:?}, expected \"none\" or \"zstd\"."
+            )),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// How snapshots are written. Snapshots are read whichever format they were written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    /// Plain bincode, which every version of Y-Sweet can read.
+    #[default]
+    Legacy,
+    /// The container, which records a checksum and the state vector and can be
+    /// compressed, but cannot be read by versions before it existed.
+    Container(Compression),
+}
+
+impl SnapshotFormat {
+    /// Only the container can record compression, so compressing implies the container.
+    pub fn new(container: bool, compression: Compression) -> Self {
+        if container || compression != Compression::None {
+            SnapshotFormat::Container(compression)
+        } else {
+            SnapshotFormat::Legacy
+        }
+    }
+}
+
+/// What a snapshot's container says about it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// 0 for snapshots written before the container existed.
+    pub format_version: u8,
+    pub compression: Compression,
+    /// Milliseconds since the Unix epoch, if recorded.
+    pub created_at_millis: Option<u64>,
+    /// The v1-encoded state vector of the doc, if recorded.
+    pub state_vector: Option<Vec<u8>>,
+}
+
+pub fn encode(
+    data: &SnapshotData,
+    state_vector: &[u8],
+    created_at_millis: u64,
+    format: SnapshotFormat,
+) -> Result<Vec<u8>, SnapshotError> {
+    let compression = match format {
+        SnapshotFormat::Legacy => return Ok(bincode::serialize(data)?),
+        SnapshotFormat::Container(compression) => compression,
+    };
+    let payload = compression.compress(bincode::serialize(data)?);
+
+    let mut body = Vec::with_capacity(1 + 8 + 4 + state_vector.len() + payload.len());
+    body.push(compression.id());
+    body.extend_from_slice(&created_at_millis.to_le_bytes());
+    body.extend_from_slice(&(state_vector.len() as u32).to_le_bytes());
+    body.extend_from_slice(state_vector);
+    body.extend_from_slice(&payload);
+
+    let mut snapshot = Vec::with_capacity(MAGIC.len() + 1 + CHECKSUM_LEN + body.len());
+    snapshot.extend_from_slice(MAGIC);
+    snapshot.push(FORMAT_VERSION);
+    snapshot.extend_from_slice(&Sha256::digest(&body));
+    snapshot.extend_from_slice(&body);
+    Ok(snapshot)
+}
+
+pub fn decode(snapshot: &[u8]) -> Result<SnapshotData, SnapshotError> {
+    decode_with_info(snapshot).map(|(_, data)| data)
+}
+
+pub fn decode_with_info(snapshot: &[u8]) -> Result<(SnapshotInfo, SnapshotData), SnapshotError> {
+    if !snapshot.starts_with(MAGIC) {
+        let info = SnapshotInfo {
+            format_version: 0,
+            compression: Compression::None,
+            created_at_millis: None,
+            state_vector: None,
+        };
+        return Ok((info, bincode::deserialize(snapshot)?));
+    }
+
+    let mut reader = Reader(&snapshot[MAGIC.len()..]);
+    let format_version = reader.take(1)?[0];
+    match format_version {
+        1 => {
+            let compression = Compression::from_id(reader.take(1)?[0])?;
+            let data = bincode::deserialize(&compression.decompress(reader.0)?)?;
+            let info = SnapshotInfo {
+                format_version,
+                compression,
+                created_at_millis: None,
+                state_vector: None,
+            };
+            Ok((info, data))
+        }
+        2 => {
+            let checksum = reader.take(CHECKSUM_LEN)?;
+            if Sha256::digest(reader.0).as_slice() != checksum {
+                return Err(SnapshotError::ChecksumMismatch);
+            }
+
+            let compression = Compression::from_id(reader.take(1)?[0])?;
+            let created_at_millis = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+            let state_vector_len = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+            let state_vector = reader.take(state_vector_len as usize)?.to_vec();
+            let data = bincode::deserialize(&compression.decompress(reader.0)?)?;
+            let info = SnapshotInfo {
+                format_version,
+                compression,
+                created_at_millis: Some(created_at_millis),
+                state_vector: Some(state_vector),
+            };
+            Ok((info, data))
+        }
+        _ => Err(SnapshotError::UnsupportedVersion(format_version)),
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> SnapshotData {
+        (0..100u32)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 64]))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_compressed_and_uncompressed() {
+        let data = sample();
+
+        let plain = encode(
+            &data,
+            &[1, 2, 3],
+            1234,
+            SnapshotFormat::Container(Compression::None),
+        )
+        .unwrap();
+        let (info, decoded) = decode_with_info(&plain).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(
+            info,
+            SnapshotInfo {
+                format_version: 2,
+                compression: Compression::None,
+                created_at_millis: Some(1234),
+                state_vector: Some(vec![1, 2, 3]),
+            }
+        );
+
+        let compressed = encode(
+            &data,
+            &[1, 2, 3],
+            1234,
+            SnapshotFormat::Container(Compression::Zstd),
+        )
+        .unwrap();
+        assert!(compressed.len() < plain.len());
+        let (info, decoded) = decode_with_info(&compressed).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(info.compression, Compression::Zstd);
+    }
+
+    #[test]
+    fn compression_implies_container() {
+        assert_eq!(
+            SnapshotFormat::new(false, Compression::None),
+            SnapshotFormat::Legacy
+        );
+        assert_eq!(
+            SnapshotFormat::new(true, Compression::None),
+            SnapshotFormat::Container(Compression::None)
+        );
+        assert_eq!(
+            SnapshotFormat::new(false, Compression::Zstd),
+            SnapshotFormat::Container(Compression::Zstd)
+        );
+    }
+
+    #[test]
+    fn decodes_legacy_snapshots() {
+        let data = sample();
+        let legacy = bincode::serialize(&data).unwrap();
+        assert_eq!(
+            encode(&data, &[1, 2, 3], 1234, SnapshotFormat::Legacy).unwrap(),
+            legacy
+        );
+        let (info, decoded) = decode_with_info(&legacy).unwrap();
+        assert_eq!(info.format_version, 0);
+        assert_eq!(decoded, data);
+
+        let empty = bincode::serialize(&SnapshotData::new()).unwrap();
+        assert!(decode(&empty).unwrap().is_empty());
+
+        let mut v1 = MAGIC.to_vec();
+        v1.extend_from_slice(&[1, Compression::Zstd.id()]);
+        v1.extend_from_slice(&Compression::Zstd.compress(legacy));
+        let (info, decoded) = decode_with_info(&v1).unwrap();
+        assert_eq!(info.format_version, 1);
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn rejects_corrupt_snapshots() {
+        let snapshot = encode(
+            &sample(),
+            &[],
+            0,
+            SnapshotFormat::Container(Compression::Zstd),
+        )
+        .unwrap();
+
+        let mut flipped = snapshot.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        assert!(matches!(
+            decode(&flipped),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        assert!(matches!(
+            decode(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            decode(&snapshot[..20]),
+            Err(SnapshotError::Truncated)
+        ));
+
+        let mut future = snapshot.clone();
+        future[MAGIC.len()] = 9;
+        assert!(matches!(
+            decode(&future),
+            Err(SnapshotError::UnsupportedVersion(9))
+        ));
+    }
+}
@@ -0,0 +1,499 @@
+use super::{ExpectedVersion, Result, Store, StoreError, PREVIOUS_VERSION_SUFFIX};
+use crate::auth::BASE64_CUSTOM;
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+
+/// Marks an object written by `EncryptedStore`. The last byte is the envelope format version.
+const MAGIC: &[u8; 4] = b"YSE\x01";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+/// Master keys that wrap the per-object data keys, identified by a key ID that is
+/// recorded in the header of every encrypted object.
+///
+/// New objects are always encrypted with the first key. The remaining keys are only
+/// used to read objects written before a rotation.
+#[derive(Clone)]
+pub struct EncryptionKeys {
+    keys: Vec<(String, Aes256Gcm)>,
+}
+
+impl EncryptionKeys {
+    /// Parses a comma-separated list of `key_id:base64_key` entries, where each key is
+    /// 32 bytes of random data, e.g. from `openssl rand -base64 32`.
+    pub fn parse(keys: &str) -> anyhow::Result<Self> {
+        let keys = keys
+            .split(',')
+            .map(|entry| {
+                let (key_id, key) = entry
+                    .trim()
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Encryption keys must have the form key_id:key."))?;
+                if key_id.is_empty() || key_id.len() > u8::MAX as usize {
+                    bail!("Encryption key IDs must be between 1 and 255 bytes long.");
+                }
+                let key = BASE64_CUSTOM
+                    .decode(key.as_bytes())
+                    .map_err(|_| anyhow!("Encryption key {} is not valid base64.", key_id))?;
+                if key.len() != KEY_LEN {
+                    bail!("Encryption key {} must be {} bytes long.", key_id, KEY_LEN);
+                }
+                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+                Ok((key_id.to_owned(), cipher))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { keys })
+    }
+
+    fn active(&self) -> &(String, Aes256Gcm) {
+        // `parse` always produces at least one key, because splitting never yields
+        // an empty iterator.
+        &self.keys[0]
+    }
+
+    fn get(&self, key_id: &str) -> Option<&Aes256Gcm> {
+        self.keys
+            .iter()
+            .find(|(id, _)| id == key_id)
+            .map(|(_, cipher)| cipher)
+    }
+}
+
+/// Wraps another store and encrypts objects at rest with AES-256-GCM.
+///
+/// Each object is encrypted with a fresh data key, which is itself encrypted
+/// ("wrapped") with the active master key and stored alongside the object:
+///
+/// ```text
+/// magic (4) | key ID length (1) | key ID | wrap nonce (12) | wrapped data key (48)
+///           | data nonce (12) | ciphertext
+/// ```
+///
+/// The object key is authenticated along with the ciphertext, so an object cannot be
+/// moved to another key without being detected. Objects without the envelope are
+/// rejected, since anyone who can write to the underlying store could otherwise plant
+/// plaintext; see `with_plaintext_reads` for stores that predate encryption.
+pub struct EncryptedStore {
+    inner: Box<dyn Store>,
+    keys: EncryptionKeys,
+    plaintext_reads: bool,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Box<dyn Store>, keys: EncryptionKeys) -> Self {
+        Self {
+            inner,
+            keys,
+            plaintext_reads: false,
+        }
+    }
+
+    /// Reads objects that were stored before encryption was enabled as-is, rather than
+    /// rejecting them. They are encrypted the next time they are written.
+    pub fn with_plaintext_reads(mut self, plaintext_reads: bool) -> Self {
+        self.plaintext_reads = plaintext_reads;
+        self
+    }
+
+    fn encrypt(&self, key: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let (key_id, master) = self.keys.active();
+
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let wrap_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped_key = master
+            .encrypt(
+                &wrap_nonce,
+                Payload {
+                    msg: &data_key,
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| StoreError::Encryption("Failed to wrap data key.".to_string()))?;
+
+        let data_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(
+                &data_nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: associated_data(key).as_bytes(),
+                },
+            )
+            .map_err(|_| StoreError::Encryption("Failed to encrypt object.".to_string()))?;
+
+        let mut result = Vec::with_capacity(
+            MAGIC.len() + 1 + key_id.len() + 2 * NONCE_LEN + wrapped_key.len() + ciphertext.len(),
+        );
+        result.extend_from_slice(MAGIC);
+        result.push(key_id.len() as u8);
+        result.extend_from_slice(key_id.as_bytes());
+        result.extend_from_slice(&wrap_nonce);
+        result.extend_from_slice(&wrapped_key);
+        result.extend_from_slice(&data_nonce);
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    fn decrypt(&self, key: &str, value: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(envelope) = value.strip_prefix(MAGIC) else {
+            if self.plaintext_reads {
+                return Ok(value);
+            }
+            return Err(StoreError::Encryption(format!(
+                "Object {} is not encrypted.",
+                key
+            )));
+        };
+
+        let malformed = || StoreError::Encryption(format!("Malformed encrypted object {}.", key));
+        let (&key_id_len, rest) = envelope.split_first().ok_or_else(malformed)?;
+        let (key_id, rest) = rest
+            .split_at_checked(key_id_len as usize)
+            .ok_or_else(malformed)?;
+        let (wrap_nonce, rest) = rest.split_at_checked(NONCE_LEN).ok_or_else(malformed)?;
+        let (wrapped_key, rest) = rest
+            .split_at_checked(KEY_LEN + TAG_LEN)
+            .ok_or_else(malformed)?;
+        let (data_nonce, ciphertext) = rest.split_at_checked(NONCE_LEN).ok_or_else(malformed)?;
+
+        let key_id = std::str::from_utf8(key_id).map_err(|_| malformed())?;
+        let master = self.keys.get(key_id).ok_or_else(|| {
+            StoreError::Encryption(format!(
+                "Object {} is encrypted with unknown key {}.",
+                key, key_id
+            ))
+        })?;
+
+        let data_key = master
+            .decrypt(
+                Nonce::from_slice(wrap_nonce),
+                Payload {
+                    msg: wrapped_key,
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                StoreError::Encryption(format!("Failed to unwrap data key of {}.", key))
+            })?;
+
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key))
+            .decrypt(
+                Nonce::from_slice(data_nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data(key).as_bytes(),
+                },
+            )
+            .map_err(|_| StoreError::Encryption(format!("Failed to decrypt {}.", key)))
+    }
+
+    async fn init(&self) -> Result<()> {
+        self.inner.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .get(key)
+            .await?
+            .map(|value| self.decrypt(key, value))
+            .transpose()
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        let value = self.encrypt(key, &value)?;
+        self.inner.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.inner.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.inner
+            .get_versioned(key)
+            .await?
+            .map(|(value, version)| Ok((self.decrypt(key, value)?, version)))
+            .transpose()
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        self.inner.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        let value = self.encrypt(key, &value)?;
+        self.inner.set_if(key, value, expected).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.inner.list_page(prefix, start_after, limit).await
+    }
+}
+
+/// The previous version of an object is a copy of the object, so it is authenticated
+/// under the object's own key.
+fn associated_data(key: &str) -> &str {
+    key.strip_suffix(PREVIOUS_VERSION_SUFFIX).unwrap_or(key)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Store for EncryptedStore {
+    async fn init(&self) -> Result<()> {
+        self.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.exists(key).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        self.set_if(key, value, expected).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.list(prefix).await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.list_page(prefix, start_after, limit).await
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
+impl Store for EncryptedStore {
+    async fn init(&self) -> Result<()> {
+        self.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.exists(key).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        self.set_if(key, value, expected).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.list(prefix).await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.list_page(prefix, start_after, limit).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dashmap::DashMap;
+    use std::sync::Arc;
+
+    #[derive(Default, Clone)]
+    struct MemoryStore {
+        data: Arc<DashMap<String, Vec<u8>>>,
+    }
+
+    #[cfg_attr(not(feature = "single-threaded"), async_trait)]
+    #[cfg_attr(feature = "single-threaded", async_trait(?Send))]
+    impl Store for MemoryStore {
+        async fn init(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.data.get(key).map(|v| v.clone()))
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+            self.data.insert(key.to_owned(), value);
+            Ok(())
+        }
+
+        async fn remove(&self, key: &str) -> Result<()> {
+            self.data.remove(key);
+            Ok(())
+        }
+
+        async fn exists(&self, key: &str) -> Result<bool> {
+            Ok(self.data.contains_key(key))
+        }
+    }
+
+    const KEY_1: &str = "key1:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const KEY_2: &str = "key2:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+    fn encrypted(inner: &MemoryStore, keys: &str) -> EncryptedStore {
+        EncryptedStore::new(
+            Box::new(inner.clone()),
+            EncryptionKeys::parse(keys).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_and_rotation() {
+        let inner = MemoryStore::default();
+        let store = encrypted(&inner, KEY_1);
+
+        store
+            .set("doc/data.ysweet", b"hello".to_vec())
+            .await
+            .unwrap();
+        let stored = inner.data.get("doc/data.ysweet").unwrap().clone();
+        assert!(stored.starts_with(MAGIC));
+        assert!(!stored.windows(5).any(|w| w == b"hello"));
+        assert_eq!(
+            store.get("doc/data.ysweet").await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+
+        // After rotating, objects written with the old key can still be read.
+        let rotated = encrypted(&inner, &format!("{KEY_2},{KEY_1}"));
+        assert_eq!(
+            rotated.get("doc/data.ysweet").await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+
+        // Without the old key, they cannot.
+        let without_old_key = encrypted(&inner, KEY_2);
+        assert!(matches!(
+            without_old_key.get("doc/data.ysweet").await,
+            Err(StoreError::Encryption(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_tampering() {
+        let inner = MemoryStore::default();
+        let store = encrypted(&inner, KEY_1);
+        store
+            .set("doc1/data.ysweet", b"hello".to_vec())
+            .await
+            .unwrap();
+
+        // Moving an object to another key is detected.
+        let stored = inner.data.get("doc1/data.ysweet").unwrap().clone();
+        inner
+            .data
+            .insert("doc2/data.ysweet".to_owned(), stored.clone());
+        assert!(store.get("doc2/data.ysweet").await.is_err());
+
+        // So is modifying the ciphertext.
+        let mut modified = stored;
+        *modified.last_mut().unwrap() ^= 1;
+        inner.data.insert("doc1/data.ysweet".to_owned(), modified);
+        assert!(store.get("doc1/data.ysweet").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reads_unencrypted_objects() {
+        let inner = MemoryStore::default();
+        inner
+            .data
+            .insert("doc/data.ysweet".to_owned(), b"plain".to_vec());
+        let store = encrypted(&inner, KEY_1);
+        assert!(matches!(
+            store.get("doc/data.ysweet").await,
+            Err(StoreError::Encryption(_))
+        ));
+
+        let store = encrypted(&inner, KEY_1).with_plaintext_reads(true);
+        assert_eq!(
+            store.get("doc/data.ysweet").await.unwrap(),
+            Some(b"plain".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert!(EncryptionKeys::parse(KEY_1).is_ok());
+        assert!(EncryptionKeys::parse("key1").is_err());
+        assert!(EncryptionKeys::parse("key1:AAAA").is_err());
+        assert!(EncryptionKeys::parse(&format!("{KEY_1},")).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+pub mod encrypted;
 pub mod s3;
 
 use async_trait::async_trait;
@@ -13,10 +14,117 @@ pub enum StoreError {
     NotAuthorized(String),
     #[error("Error connecting to store. {0}")]
     ConnectionError(String),
+    /// The store is temporarily unable to handle the request, e.g. it is throttling
+    /// or overloaded. The same request may succeed later.
+    #[error("Store is temporarily unavailable. {0}")]
+    Unavailable(String),
+    /// The store rejected the request in a way that retrying will not fix.
+    #[error("Unexpected response from store. {0}")]
+    UnexpectedResponse(String),
+    /// A conditional write was rejected because the object is not at the expected
+    /// version.
+    #[error("Object was modified concurrently. {0}")]
+    Conflict(String),
+    #[error("Error encrypting or decrypting object. {0}")]
+    Encryption(String),
+    #[error("Operation is not supported by this store. {0}")]
+    Unsupported(String),
+}
+
+impl StoreError {
+    /// Whether the same request may succeed if it is retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            StoreError::ConnectionError(_) | StoreError::Unavailable(_)
+        )
+    }
 }
 
 pub type Result<T> = std::result::Result<T, StoreError>;
 
+/// Stores that keep the previous version of an object when overwriting it expose
+/// that version under the object's key with this suffix appended. Readers can fall
+/// back to it if the current version turns out to be unreadable.
+pub const PREVIOUS_VERSION_SUFFIX: &str = ".prev";
+
+/// The key suffix under which a doc's snapshot is stored; see `SyncKv`.
+pub const DOC_SNAPSHOT_SUFFIX: &str = "/data.ysweet";
+
+/// The key suffix under which a doc's metadata is stored, next to its snapshot.
+pub const DOC_METADATA_SUFFIX: &str = "/meta.json";
+
+// How many keys `list_doc_ids_page` asks the store for at once.
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// Returns the IDs of all docs that have a snapshot in the store, in lexicographic
+/// order.
+pub async fn list_doc_ids(store: &dyn Store) -> Result<Vec<String>> {
+    list_doc_ids_with_prefix(store, "").await
+}
+
+/// Like `list_doc_ids`, but only lists the docs whose IDs start with `prefix`.
+pub async fn list_doc_ids_with_prefix(store: &dyn Store, prefix: &str) -> Result<Vec<String>> {
+    Ok(store
+        .list(prefix)
+        .await?
+        .into_iter()
+        .filter_map(|key| key.strip_suffix(DOC_SNAPSHOT_SUFFIX).map(str::to_owned))
+        .collect())
+}
+
+/// Returns the IDs of up to `limit` docs that have a snapshot in the store and whose IDs
+/// start with `prefix`, in the order of their snapshot keys. With `after`, the listing
+/// starts after that doc. The store is listed a page at a time, so only about as many
+/// keys are read as are needed.
+pub async fn list_doc_ids_page(
+    store: &dyn Store,
+    prefix: &str,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<Vec<String>> {
+    let mut doc_ids = Vec::new();
+    let mut start_after = after.map(|doc_id| format!("{}{}", doc_id, DOC_SNAPSHOT_SUFFIX));
+    while doc_ids.len() < limit {
+        let keys = store
+            .list_page(prefix, start_after.as_deref(), LIST_PAGE_SIZE)
+            .await?;
+        let page_len = keys.len();
+        start_after = keys.last().cloned();
+        doc_ids.extend(
+            keys.into_iter()
+                .filter_map(|key| key.strip_suffix(DOC_SNAPSHOT_SUFFIX).map(str::to_owned))
+                .take(limit - doc_ids.len()),
+        );
+        if page_len < LIST_PAGE_SIZE {
+            break;
+        }
+    }
+    Ok(doc_ids)
+}
+
+/// The state an object must be in for a conditional write to go through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExpectedVersion {
+    /// The object must not exist.
+    Absent,
+    /// The object's current version must be this one, as returned by the store when
+    /// the object was read or written.
+    Version(String),
+    /// Write unconditionally.
+    Any,
+}
+
+impl ExpectedVersion {
+    /// What to expect of an object next, given the version the store reported for it.
+    pub fn from_store_version(version: Option<String>) -> Self {
+        match version {
+            Some(version) => ExpectedVersion::Version(version),
+            None => ExpectedVersion::Any,
+        }
+    }
+}
+
 #[cfg(target_arch = "wasm32")]
 #[async_trait(?Send)]
 pub trait Store: 'static {
@@ -25,6 +133,56 @@ pub trait Store: 'static {
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()>;
     async fn remove(&self, key: &str) -> Result<()>;
     async fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Like `get`, but also returns the object's version, if the store tracks versions.
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        Ok(self.get(key).await?.map(|value| (value, None)))
+    }
+
+    /// Returns the object's version without its value: `None` if the object does not
+    /// exist, and `Some(None)` if the store does not track versions. Stores that can
+    /// look up a version more cheaply than fetching the object override this.
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        Ok(self.get_versioned(key).await?.map(|(_, version)| version))
+    }
+
+    /// Writes the object only if it is in the `expected` state, and returns its new
+    /// version, if the store tracks versions. Fails with `StoreError::Conflict` if the
+    /// object is in a different state. Stores that do not support conditional writes
+    /// write unconditionally.
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        _expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        self.set(key, value).await?;
+        Ok(None)
+    }
+
+    /// Returns the keys of all objects whose key starts with `prefix`, in
+    /// lexicographic order.
+    async fn list(&self, _prefix: &str) -> Result<Vec<String>> {
+        Err(StoreError::Unsupported("Listing objects.".to_string()))
+    }
+
+    /// Like `list`, but returns at most `limit` keys, starting after the key
+    /// `start_after`. Stores that can list a range of keys override this; by default,
+    /// all keys are listed and the page is taken from them.
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .list(prefix)
+            .await?
+            .into_iter()
+            .filter(|key| start_after.is_none_or(|start_after| key.as_str() > start_after))
+            .take(limit)
+            .collect())
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -35,4 +193,54 @@ pub trait Store: Send + Sync {
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()>;
     async fn remove(&self, key: &str) -> Result<()>;
     async fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Like `get`, but also returns the object's version, if the store tracks versions.
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        Ok(self.get(key).await?.map(|value| (value, None)))
+    }
+
+    /// Returns the object's version without its value: `None` if the object does not
+    /// exist, and `Some(None)` if the store does not track versions. Stores that can
+    /// look up a version more cheaply than fetching the object override this.
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        Ok(self.get_versioned(key).await?.map(|(_, version)| version))
+    }
+
+    /// Writes the object only if it is in the `expected` state, and returns its new
+    /// version, if the store tracks versions. Fails with `StoreError::Conflict` if the
+    /// object is in a different state. Stores that do not support conditional writes
+    /// write unconditionally.
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        _expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        self.set(key, value).await?;
+        Ok(None)
+    }
+
+    /// Returns the keys of all objects whose key starts with `prefix`, in
+    /// lexicographic order.
+    async fn list(&self, _prefix: &str) -> Result<Vec<String>> {
+        Err(StoreError::Unsupported("Listing objects.".to_string()))
+    }
+
+    /// Like `list`, but returns at most `limit` keys, starting after the key
+    /// `start_after`. Stores that can list a range of keys override this; by default,
+    /// all keys are listed and the page is taken from them.
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .list(prefix)
+            .await?
+            .into_iter()
+            .filter(|key| start_after.is_none_or(|start_after| key.as_str() > start_after))
+            .take(limit)
+            .collect())
+    }
 }
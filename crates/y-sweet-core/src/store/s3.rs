@@ -1,9 +1,12 @@
-use super::{Result, StoreError};
+use super::{ExpectedVersion, Result, StoreError};
 use crate::store::Store;
 use async_trait::async_trait;
 use bytes::Bytes;
-use reqwest::{Client, Method, Response, StatusCode, Url};
-use rusty_s3::{Bucket, Credentials, S3Action};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ETAG, IF_MATCH, IF_NONE_MATCH},
+    Client, Method, Response, StatusCode, Url,
+};
+use rusty_s3::{actions::ListObjectsV2, Bucket, Credentials, S3Action};
 use serde::{Deserialize, Serialize};
 use std::sync::OnceLock;
 use std::time::Duration;
@@ -21,16 +24,69 @@ pub struct S3Config {
 
     // Use old path-style URLs, needed to support some S3-compatible APIs (including some minio setups)
     pub path_style: bool,
+
+    #[serde(default)]
+    pub retry: S3RetryConfig,
+}
+
+/// Timeout and retry behavior for requests to the S3-compatible API.
+///
+/// Timeouts and retries only apply to native builds; on wasm32, each request is
+/// made once and the host runtime's own limits apply.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct S3RetryConfig {
+    /// Time limit for a single attempt, including reading the response body.
+    pub request_timeout: Duration,
+    /// Total number of attempts for a request, including the first one.
+    pub max_attempts: u32,
+    /// Upper bound of the delay before the first retry. The bound doubles with each
+    /// retry, up to `max_backoff`, and the actual delay is picked uniformly below it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for S3RetryConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout: Duration::from_secs(30),
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl S3RetryConfig {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn backoff(&self, attempt: u32) -> Duration {
+        use rand::Rng;
+
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+    }
 }
 
 const PRESIGNED_URL_DURATION: Duration = Duration::from_secs(60 * 60);
 
+/// A successful response whose body has been read in full.
+struct S3Response {
+    etag: Option<String>,
+    body: Bytes,
+}
+
 pub struct S3Store {
     bucket: Bucket,
     _bucket_checked: OnceLock<()>,
     client: Client,
     credentials: Credentials,
     prefix: Option<String>,
+    // Only used by native builds; see `S3RetryConfig`.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    retry: S3RetryConfig,
 }
 
 impl S3Store {
@@ -55,6 +111,12 @@ impl S3Store {
 
         let bucket = Bucket::new(endpoint, path_style, config.bucket, config.region)
             .expect("Url has a valid scheme and host");
+        #[cfg(not(target_arch = "wasm32"))]
+        let client = Client::builder()
+            .timeout(config.retry.request_timeout)
+            .build()
+            .expect("TLS backend can be initialized");
+        #[cfg(target_arch = "wasm32")]
         let client = Client::new();
 
         S3Store {
@@ -63,20 +125,55 @@ impl S3Store {
             client,
             credentials,
             prefix: config.bucket_prefix,
+            retry: config.retry,
         }
     }
 
+    /// Makes the request and reads the response body, retrying retryable failures of
+    /// either with jittered exponential backoff.
     async fn store_request<'a, A: S3Action<'a>>(
         &self,
         method: Method,
         action: A,
+        headers: HeaderMap,
         body: Option<Vec<u8>>,
-    ) -> Result<Response> {
+    ) -> Result<S3Response> {
         let url = action.sign_with_time(PRESIGNED_URL_DURATION, &OffsetDateTime::now_utc());
-        let mut request = self.client.request(method, url);
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let mut attempt = 1;
+            loop {
+                let result = self
+                    .store_request_once(method.clone(), url.clone(), headers.clone(), body.clone())
+                    .await;
+                match result {
+                    Err(e) if e.is_retryable() && attempt < self.retry.max_attempts => {
+                        let backoff = self.retry.backoff(attempt - 1);
+                        tracing::warn!(?e, attempt, ?backoff, "Retrying S3 request");
+                        tokio::time::sleep(backoff).await;
+                        attempt += 1;
+                    }
+                    result => return result,
+                }
+            }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        self.store_request_once(method, url, headers, body).await
+    }
+
+    async fn store_request_once(
+        &self,
+        method: Method,
+        url: Url,
+        headers: HeaderMap,
+        body: Option<Vec<u8>>,
+    ) -> Result<S3Response> {
+        let mut request = self.client.request(method, url).headers(headers);
 
         request = if let Some(body) = body {
-            request.body(body.to_vec())
+            request.body(body)
         } else {
             request
         };
@@ -89,7 +186,14 @@ impl S3Store {
         };
 
         match response.status() {
-            StatusCode::OK => Ok(response),
+            StatusCode::OK => {
+                let etag = Self::etag(&response);
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| StoreError::ConnectionError(e.to_string()))?;
+                Ok(S3Response { etag, body })
+            }
             StatusCode::NOT_FOUND => Err(StoreError::DoesNotExist(
                 "Received NOT_FOUND from S3-compatible API.".to_string(),
             )),
@@ -99,17 +203,21 @@ impl S3Store {
             StatusCode::UNAUTHORIZED => Err(StoreError::NotAuthorized(
                 "Received UNAUTHORIZED from S3-compatible API.".to_string(),
             )),
-            _ => Err(StoreError::ConnectionError(format!(
+            // A failed If-Match or If-None-Match precondition is a 412; S3 returns a 409
+            // when a concurrent conditional write to the same key wins the race.
+            StatusCode::PRECONDITION_FAILED | StatusCode::CONFLICT => Err(StoreError::Conflict(
+                format!("Received {} from S3-compatible API.", response.status()),
+            )),
+            // S3 asks clients to slow down with a 503, and other S3-compatible APIs use
+            // 429. Server errors and request timeouts are also worth retrying.
+            status if is_retryable_status(status) => Err(StoreError::Unavailable(format!(
                 "Received {} from S3-compatible API.",
-                response.status()
+                status
+            ))),
+            status => Err(StoreError::UnexpectedResponse(format!(
+                "Received {} from S3-compatible API.",
+                status
             ))),
-        }
-    }
-
-    async fn read_response_bytes(response: Response) -> Result<Bytes> {
-        match response.bytes().await {
-            Ok(bytes) => Ok(bytes),
-            Err(e) => Err(StoreError::ConnectionError(e.to_string())),
         }
     }
 
@@ -119,7 +227,9 @@ impl S3Store {
         }
 
         let action = self.bucket.head_bucket(Some(&self.credentials));
-        let result = self.store_request(Method::HEAD, action, None).await;
+        let result = self
+            .store_request(Method::HEAD, action, HeaderMap::new(), None)
+            .await;
 
         match result {
             // Normally a 404 indicates that we are attempting to fetch an object that does
@@ -152,13 +262,12 @@ impl S3Store {
         let object_get = self
             .bucket
             .get_object(Some(&self.credentials), &prefixed_key);
-        let response = self.store_request(Method::GET, object_get, None).await;
+        let response = self
+            .store_request(Method::GET, object_get, HeaderMap::new(), None)
+            .await;
 
         match response {
-            Ok(response) => {
-                let result = Self::read_response_bytes(response).await?;
-                Ok(Some(result.to_vec()))
-            }
+            Ok(response) => Ok(Some(response.body.to_vec())),
             Err(StoreError::DoesNotExist(_)) => Ok(None),
             Err(e) => Err(e),
         }
@@ -170,35 +279,162 @@ impl S3Store {
         let action = self
             .bucket
             .put_object(Some(&self.credentials), &prefixed_key);
-        self.store_request(Method::PUT, action, Some(value)).await?;
+        self.store_request(Method::PUT, action, HeaderMap::new(), Some(value))
+            .await?;
         Ok(())
     }
 
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.init().await?;
+        let prefixed_key = self.prefixed_key(key);
+        let object_get = self
+            .bucket
+            .get_object(Some(&self.credentials), &prefixed_key);
+        let response = self
+            .store_request(Method::GET, object_get, HeaderMap::new(), None)
+            .await;
+
+        match response {
+            Ok(response) => Ok(Some((response.body.to_vec(), response.etag))),
+            Err(StoreError::DoesNotExist(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        self.init().await?;
+        let prefixed_key = self.prefixed_key(key);
+        let action = self
+            .bucket
+            .put_object(Some(&self.credentials), &prefixed_key);
+
+        let mut headers = HeaderMap::new();
+        match expected {
+            ExpectedVersion::Absent => {
+                headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+            }
+            ExpectedVersion::Version(etag) => {
+                let etag = HeaderValue::from_str(etag)
+                    .map_err(|e| StoreError::UnexpectedResponse(format!("Invalid ETag. {e}")))?;
+                headers.insert(IF_MATCH, etag);
+            }
+            ExpectedVersion::Any => {}
+        }
+
+        let response = self
+            .store_request(Method::PUT, action, headers, Some(value))
+            .await?;
+        Ok(response.etag)
+    }
+
+    fn etag(response: &Response) -> Option<String> {
+        response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| etag.to_owned())
+    }
+
     async fn remove(&self, key: &str) -> Result<()> {
         self.init().await?;
         let prefixed_key = self.prefixed_key(key);
         let action = self
             .bucket
             .delete_object(Some(&self.credentials), &prefixed_key);
-        self.store_request(Method::DELETE, action, None).await?;
+        self.store_request(Method::DELETE, action, HeaderMap::new(), None)
+            .await?;
         Ok(())
     }
 
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.list_page(prefix, None, usize::MAX).await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.init().await?;
+        let prefixed = self.prefixed_key(prefix);
+        let start_after = start_after.map(|key| self.prefixed_key(key));
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(Some(&self.credentials));
+            action.with_prefix(prefixed.as_str());
+            // S3 returns at most 1000 keys per request anyway.
+            action.with_max_keys((limit - keys.len()).min(1000));
+            match (&continuation_token, &start_after) {
+                (Some(token), _) => action.with_continuation_token(String::clone(token)),
+                (None, Some(start_after)) => action.with_start_after(start_after.as_str()),
+                (None, None) => {}
+            }
+            let response = self
+                .store_request(Method::GET, action, HeaderMap::new(), None)
+                .await?;
+            let body = std::str::from_utf8(&response.body)
+                .map_err(|e| StoreError::UnexpectedResponse(e.to_string()))?;
+            let page = ListObjectsV2::parse_response(body)
+                .map_err(|e| StoreError::UnexpectedResponse(e.to_string()))?;
+
+            keys.extend(page.contents.into_iter().filter_map(|object| {
+                match &self.prefix {
+                    Some(path_prefix) => object
+                        .key
+                        .strip_prefix(path_prefix.as_str())
+                        .and_then(|key| key.strip_prefix('/'))
+                        .map(str::to_owned),
+                    None => Some(object.key),
+                }
+            }));
+
+            match page.next_continuation_token {
+                Some(token) if keys.len() < limit => continuation_token = Some(token),
+                _ => return Ok(keys),
+            }
+        }
+    }
+
     async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.get_version(key).await?.is_some())
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
         self.init().await?;
         let prefixed_key = self.prefixed_key(key);
         let action = self
             .bucket
             .head_object(Some(&self.credentials), &prefixed_key);
-        let response = self.store_request(Method::HEAD, action, None).await;
+        let response = self
+            .store_request(Method::HEAD, action, HeaderMap::new(), None)
+            .await;
         match response {
-            Ok(_) => Ok(true),
-            Err(StoreError::DoesNotExist(_)) => Ok(false),
+            Ok(response) => Ok(Some(response.etag)),
+            Err(StoreError::DoesNotExist(_)) => Ok(None),
             Err(e) => Err(e),
         }
     }
 }
 
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 #[async_trait]
 impl Store for S3Store {
@@ -221,6 +457,36 @@ impl Store for S3Store {
     async fn exists(&self, key: &str) -> Result<bool> {
         self.exists(key).await
     }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        self.set_if(key, value, expected).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.list(prefix).await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.list_page(prefix, start_after, limit).await
+    }
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -245,4 +511,79 @@ impl Store for S3Store {
     async fn exists(&self, key: &str) -> Result<bool> {
         self.exists(key).await
     }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        self.set_if(key, value, expected).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.list(prefix).await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.list_page(prefix, start_after, limit).await
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let retry = S3RetryConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            ..S3RetryConfig::default()
+        };
+        for _ in 0..100 {
+            assert!(retry.backoff(0) <= Duration::from_millis(100));
+            assert!(retry.backoff(2) <= Duration::from_millis(400));
+            assert!(retry.backoff(10) <= Duration::from_secs(1));
+            assert!(retry.backoff(u32::MAX) <= Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn test_is_retryable_status() {
+        for status in [
+            StatusCode::REQUEST_TIMEOUT,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::GATEWAY_TIMEOUT,
+        ] {
+            assert!(is_retryable_status(status), "{status}");
+        }
+        for status in [
+            StatusCode::OK,
+            StatusCode::BAD_REQUEST,
+            StatusCode::FORBIDDEN,
+            StatusCode::NOT_FOUND,
+            StatusCode::CONFLICT,
+            StatusCode::PRECONDITION_FAILED,
+            StatusCode::NOT_IMPLEMENTED,
+        ] {
+            assert!(!is_retryable_status(status), "{status}");
+        }
+    }
 }
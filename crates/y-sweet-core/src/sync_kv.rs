@@ -1,15 +1,29 @@
-use crate::store::Store;
+use crate::{
+    api_types::CompactionReport,
+    doc_connection::DOC_NAME,
+    snapshot::{self, SnapshotFormat},
+    store::{ExpectedVersion, Store, StoreError, DOC_SNAPSHOT_SUFFIX, PREVIOUS_VERSION_SUFFIX},
+};
 use anyhow::{Context, Result};
 use std::{
     collections::BTreeMap,
     convert::Infallible,
     ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
     },
 };
-use yrs_kvstore::{DocOps, KVEntry};
+use tracing::Instrument;
+use yrs::{updates::encoder::Encode, ReadTxn, StateVector, Transact};
+use yrs_kvstore::{
+    keys::{KEYSPACE_DOC, SUB_UPDATE, V1},
+    DocOps, KVEntry,
+};
+
+/// How many times `persist` tries to write a snapshot that keeps conflicting with
+/// concurrent writes from other instances.
+const MAX_PERSIST_ATTEMPTS: u32 = 5;
 
 pub struct SyncKv {
     data: Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>,
@@ -18,25 +32,50 @@ pub struct SyncKv {
     dirty: AtomicBool,
     dirty_callback: Box<dyn Fn() + Send + Sync>,
     shutdown: AtomicBool,
+    /// Milliseconds since epoch of the last successful persist, or 0 if never persisted.
+    last_persisted_millis: AtomicU64,
+    /// The version of the stored snapshot that our data is based on. Snapshots are
+    /// only written if the stored snapshot is still at this version.
+    version: Mutex<ExpectedVersion>,
+    format: SnapshotFormat,
+    merge_callback: OnceLock<MergeCallback>,
 }
 
+/// Applies changes merged in from the store to the loaded doc; see `SyncKv::on_merge`.
+/// The loaded doc can only be shared between threads with the `sync` feature.
+#[cfg(feature = "sync")]
+pub type MergeCallback = Box<dyn Fn(&[u8]) -> bool + Send + Sync>;
+#[cfg(not(feature = "sync"))]
+pub type MergeCallback = Box<dyn Fn(&[u8]) -> bool>;
+
 impl SyncKv {
     pub async fn new<Callback: Fn() + Send + Sync + 'static>(
         store: Option<Arc<Box<dyn Store>>>,
         key: &str,
         callback: Callback,
     ) -> Result<Self> {
-        let key = format!("{}/data.ysweet", key);
+        let key = format!("{}{}", key, DOC_SNAPSHOT_SUFFIX);
 
-        let data = if let Some(store) = &store {
-            if let Some(snapshot) = store.get(&key).await.context("Failed to get from store.")? {
+        let (data, version) = if let Some(store) = &store {
+            if let Some((snapshot, version)) = store
+                .get_versioned(&key)
+                .await
+                .context("Failed to get from store.")?
+            {
                 tracing::info!(size=?snapshot.len(), "Loaded snapshot");
-                bincode::deserialize(&snapshot).context("Failed to deserialize.")?
+                let data = match snapshot::decode(&snapshot) {
+                    Ok(data) => data,
+                    Err(e) => Self::load_previous_snapshot(store, &key)
+                        .await
+                        .ok_or(e)
+                        .context("Failed to deserialize.")?,
+                };
+                (data, ExpectedVersion::from_store_version(version))
             } else {
-                BTreeMap::new()
+                (BTreeMap::new(), ExpectedVersion::Absent)
             }
         } else {
-            BTreeMap::new()
+            (BTreeMap::new(), ExpectedVersion::Any)
         };
 
         Ok(Self {
@@ -46,9 +85,78 @@ impl SyncKv {
             dirty: AtomicBool::new(false),
             dirty_callback: Box::new(callback),
             shutdown: AtomicBool::new(false),
+            last_persisted_millis: AtomicU64::new(0),
+            version: Mutex::new(version),
+            format: SnapshotFormat::Legacy,
+            merge_callback: OnceLock::new(),
         })
     }
 
+    /// Sets the format that snapshots are persisted in. Snapshots are read regardless
+    /// of their format.
+    pub fn with_format(mut self, format: SnapshotFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the function that `persist` calls with changes that it merges in from
+    /// another instance's snapshot, as a v1 update. It applies them to the loaded doc,
+    /// which passes them on to connected clients and back to us, and returns `false` if
+    /// there is no loaded doc, in which case we add the changes to our data directly.
+    pub fn on_merge(&self, callback: MergeCallback) {
+        if self.merge_callback.set(callback).is_err() {
+            tracing::warn!("Merge callback was already set");
+        }
+    }
+
+    /// A `SyncKv` over `data` that is not backed by a store, such as the contents of a
+    /// snapshot read by other means.
+    pub fn detached(data: BTreeMap<Vec<u8>, Vec<u8>>) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(data)),
+            store: None,
+            key: String::new(),
+            dirty: AtomicBool::new(false),
+            dirty_callback: Box::new(|| ()),
+            shutdown: AtomicBool::new(false),
+            last_persisted_millis: AtomicU64::new(0),
+            version: Mutex::new(ExpectedVersion::Any),
+            format: SnapshotFormat::Legacy,
+            merge_callback: OnceLock::new(),
+        }
+    }
+
+    /// Falls back to the snapshot that the store kept before the last overwrite, if
+    /// it kept one and it can be deserialized.
+    async fn load_previous_snapshot(
+        store: &Arc<Box<dyn Store>>,
+        key: &str,
+    ) -> Option<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let previous_key = format!("{}{}", key, PREVIOUS_VERSION_SUFFIX);
+        let snapshot = match store.get(&previous_key).await {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => return None,
+            Err(e) => {
+                tracing::warn!(?e, "Failed to get previous snapshot");
+                return None;
+            }
+        };
+
+        match snapshot::decode(&snapshot) {
+            Ok(data) => {
+                tracing::warn!(
+                    size=?snapshot.len(),
+                    "Snapshot is corrupt, recovered from previous snapshot"
+                );
+                Some(data)
+            }
+            Err(e) => {
+                tracing::warn!(?e, "Previous snapshot is also corrupt");
+                None
+            }
+        }
+    }
+
     fn mark_dirty(&self) {
         if !self.shutdown.load(Ordering::SeqCst) {
             let was_updated = !self.dirty.swap(true, Ordering::SeqCst);
@@ -59,6 +167,17 @@ impl SyncKv {
     }
 
     pub async fn persist(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.persist_with(true).await
+    }
+
+    /// Like `persist`, but for a doc that is being created: if another instance has
+    /// written a snapshot since ours was loaded, this fails with `StoreError::Conflict`
+    /// instead of merging with it.
+    pub async fn persist_new(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.persist_with(false).await
+    }
+
+    async fn persist_with(&self, merge_conflicts: bool) -> Result<(), Box<dyn std::error::Error>> {
         // Only persist if actually dirty
         if !self.dirty.load(Ordering::SeqCst) {
             tracing::info!("Not persisting, no changes detected");
@@ -66,18 +185,150 @@ impl SyncKv {
         }
 
         if let Some(store) = &self.store {
-            let snapshot = {
-                let data = self.data.lock().unwrap();
-                bincode::serialize(&*data)?
-            };
-
-            tracing::info!(size=?snapshot.len(), "Persisting snapshot");
-            store.set(&self.key, snapshot).await?;
+            let mut attempt = 1;
+            loop {
+                let state_vector = self.state_vector()?.encode_v1();
+                let snapshot = {
+                    let data = self.data.lock().unwrap();
+                    snapshot::encode(
+                        &data,
+                        &state_vector,
+                        current_time_epoch_millis(),
+                        self.format,
+                    )?
+                };
+                let expected = self.version.lock().unwrap().clone();
+
+                tracing::info!(size=?snapshot.len(), "Persisting snapshot");
+                let result = store
+                    .set_if(&self.key, snapshot, &expected)
+                    .instrument(tracing::info_span!("store_set", key = %self.key))
+                    .await;
+
+                match result {
+                    Ok(version) => {
+                        *self.version.lock().unwrap() =
+                            ExpectedVersion::from_store_version(version);
+                        break;
+                    }
+                    Err(StoreError::Conflict(_))
+                        if merge_conflicts && attempt < MAX_PERSIST_ATTEMPTS =>
+                    {
+                        tracing::warn!(attempt, "Snapshot was written concurrently, merging");
+                        self.merge_from_store(store).await?;
+                        attempt += 1;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
         }
         self.dirty.store(false, Ordering::SeqCst);
+        self.last_persisted_millis
+            .store(current_time_epoch_millis(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Merges the doc in the currently stored snapshot into ours, so that writing our
+    /// snapshot does not drop changes that another instance has persisted. The changes
+    /// go through the merge callback, if any, so that the loaded doc has them too.
+    async fn merge_from_store(
+        &self,
+        store: &Arc<Box<dyn Store>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((snapshot, version)) = store.get_versioned(&self.key).await? else {
+            *self.version.lock().unwrap() = ExpectedVersion::Absent;
+            return Ok(());
+        };
+
+        let stored = Self::detached(snapshot::decode(&snapshot)?);
+        let doc = yrs::Doc::new();
+        stored.load_doc(DOC_NAME, &mut doc.transact_mut())?;
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        let applied = self
+            .merge_callback
+            .get()
+            .is_some_and(|callback| callback(&update));
+        if !applied {
+            self.push_update(DOC_NAME, &update)?;
+            self.flush_doc_with(DOC_NAME, Default::default())?;
+        }
+        *self.version.lock().unwrap() = ExpectedVersion::from_store_version(version);
         Ok(())
     }
 
+    /// The state vector of the doc, recorded in snapshots so that it can be read
+    /// without loading the doc.
+    fn state_vector(&self) -> Result<StateVector, Box<dyn std::error::Error>> {
+        match self.get_state_vector(DOC_NAME)? {
+            (state_vector, true) => Ok(state_vector.unwrap_or_default()),
+            (_, false) => {
+                let doc = yrs::Doc::new();
+                self.load_doc(DOC_NAME, &mut doc.transact_mut())?;
+                let state_vector = doc.transact().state_vector();
+                Ok(state_vector)
+            }
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    pub fn last_persisted_millis(&self) -> Option<u64> {
+        match self.last_persisted_millis.load(Ordering::SeqCst) {
+            0 => None,
+            millis => Some(millis),
+        }
+    }
+
+    /// Number of updates that have been pushed but not yet flushed into the doc state.
+    pub fn pending_update_count(&self) -> usize {
+        let map = self.data.lock().unwrap();
+        map.keys().filter(|key| is_update_key(key)).count()
+    }
+
+    /// Rebuilds the doc from its current state in a fresh `yrs::Doc` with garbage
+    /// collection enabled, and replaces the stored doc state and pending updates with
+    /// the result. This drops the contents of deleted items, which docs that are
+    /// edited with garbage collection disabled keep indefinitely.
+    ///
+    /// With `dry_run`, the data is left unchanged and only the size it would have is
+    /// reported. Otherwise the data is marked dirty, to be written by the next persist.
+    pub fn compact(&self, dry_run: bool) -> Result<CompactionReport> {
+        let mut data = self.data.lock().unwrap();
+        let size_before = data_size(&data);
+
+        // Updates pushed while compacting wait for the lock, so none are lost when
+        // the compacted data replaces ours.
+        let doc = yrs::Doc::new();
+        Self::detached(data.clone())
+            .load_doc(DOC_NAME, &mut doc.transact_mut())
+            .map_err(|e| anyhow::anyhow!("Failed to load doc: {:?}", e))?;
+
+        let compacted = Self::detached(data.clone());
+        compacted
+            .insert_doc(DOC_NAME, &doc.transact())
+            .map_err(|e| anyhow::anyhow!("Failed to write compacted doc: {:?}", e))?;
+        let mut compacted = std::mem::take(&mut *compacted.data.lock().unwrap());
+        compacted.retain(|key, _| !is_update_key(key));
+        let size_after = data_size(&compacted);
+
+        if !dry_run {
+            *data = compacted;
+            drop(data);
+            self.mark_dirty();
+        }
+
+        Ok(CompactionReport {
+            size_before,
+            size_after,
+            dry_run,
+        })
+    }
+
     #[cfg(test)]
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
         let map = self.data.lock().unwrap();
@@ -110,6 +361,22 @@ impl SyncKv {
     }
 }
 
+/// Whether `key` holds an update that has not been flushed into the doc state. Update
+/// keys have the form 01{oid:4}2{clock:4}0; see `yrs_kvstore::keys`.
+fn is_update_key(key: &[u8]) -> bool {
+    key.len() == 12 && key[0] == V1 && key[1] == KEYSPACE_DOC && key[6] == SUB_UPDATE
+}
+
+fn data_size(data: &BTreeMap<Vec<u8>, Vec<u8>>) -> usize {
+    data.iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum()
+}
+
+fn current_time_epoch_millis() -> u64 {
+    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as u64
+}
+
 impl<'d> DocOps<'d> for SyncKv {}
 
 pub struct SyncKvEntry {
@@ -204,15 +471,17 @@ impl<'a> yrs_kvstore::KVStore<'a> for SyncKv {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::store::Result;
+    use crate::{doc_sync::DocWithSyncKv, snapshot::Compression, store::Result};
     use async_trait::async_trait;
     use dashmap::DashMap;
     use std::sync::atomic::AtomicUsize;
     use tokio;
+    use yrs::{GetString, Text};
 
     #[derive(Default, Clone)]
     struct MemoryStore {
         data: Arc<DashMap<String, Vec<u8>>>,
+        versions: Arc<DashMap<String, u64>>,
     }
 
     #[cfg_attr(not(feature = "single-threaded"), async_trait)]
@@ -228,6 +497,7 @@ mod test {
 
         async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
             self.data.insert(key.to_owned(), value);
+            *self.versions.entry(key.to_owned()).or_default() += 1;
             Ok(())
         }
 
@@ -239,6 +509,35 @@ mod test {
         async fn exists(&self, key: &str) -> Result<bool> {
             Ok(self.data.contains_key(key))
         }
+
+        async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+            let version = self.versions.get(key).map(|v| v.to_string());
+            Ok(self.data.get(key).map(|v| (v.clone(), version)))
+        }
+
+        async fn set_if(
+            &self,
+            key: &str,
+            value: Vec<u8>,
+            expected: &ExpectedVersion,
+        ) -> Result<Option<String>> {
+            let current = self
+                .data
+                .contains_key(key)
+                .then(|| self.versions.get(key).map(|v| v.to_string()))
+                .flatten();
+            let matches = match expected {
+                ExpectedVersion::Absent => !self.data.contains_key(key),
+                ExpectedVersion::Version(version) => current.as_ref() == Some(version),
+                ExpectedVersion::Any => true,
+            };
+            if !matches {
+                return Err(StoreError::Conflict(key.to_owned()));
+            }
+
+            self.set(key, value).await?;
+            Ok(self.versions.get(key).map(|v| v.to_string()))
+        }
     }
 
     #[derive(Default, Clone)]
@@ -299,6 +598,11 @@ mod test {
             sync_kv.persist().await.unwrap();
         }
 
+        // Unless the container is enabled, snapshots stay readable by older versions.
+        let stored = store.data.get("foo/data.ysweet").unwrap().clone();
+        let (info, _) = snapshot::decode_with_info(&stored).unwrap();
+        assert_eq!(info.format_version, 0);
+
         {
             let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
                 .await
@@ -308,6 +612,237 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn reads_compressed_and_legacy_snapshots() {
+        let store = MemoryStore::default();
+
+        // Snapshots written before the container format are bare bincode.
+        let legacy: snapshot::SnapshotData = [(b"foo".to_vec(), b"bar".to_vec())].into();
+        store
+            .set("foo/data.ysweet", bincode::serialize(&legacy).unwrap())
+            .await
+            .unwrap();
+
+        {
+            let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+                .await
+                .unwrap()
+                .with_format(SnapshotFormat::Container(Compression::Zstd));
+            assert_eq!(sync_kv.get(b"foo"), Some(b"bar".to_vec()));
+            sync_kv.set(b"abc", b"def");
+            sync_kv.persist().await.unwrap();
+        }
+        let compressed = store.data.get("foo/data.ysweet").unwrap().clone();
+        let (info, _) = snapshot::decode_with_info(&compressed).unwrap();
+        assert_eq!(info.compression, Compression::Zstd);
+
+        let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap();
+        assert_eq!(sync_kv.get(b"foo"), Some(b"bar".to_vec()));
+        assert_eq!(sync_kv.get(b"abc"), Some(b"def".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn records_state_vector_in_snapshot() {
+        let store = MemoryStore::default();
+        let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap()
+            .with_format(SnapshotFormat::Container(Compression::None));
+        insert_text(&sync_kv, "hello");
+        sync_kv.persist().await.unwrap();
+
+        let doc = yrs::Doc::new();
+        sync_kv.load_doc(DOC_NAME, &mut doc.transact_mut()).unwrap();
+        let expected = doc.transact().state_vector().encode_v1();
+
+        let stored = store.data.get("foo/data.ysweet").unwrap().clone();
+        let (info, _) = snapshot::decode_with_info(&stored).unwrap();
+        assert_eq!(info.format_version, 2);
+        assert_eq!(info.state_vector, Some(expected));
+        assert!(info.created_at_millis.is_some());
+    }
+
+    fn text_update(text: &str) -> Vec<u8> {
+        let doc = yrs::Doc::new();
+        let root = doc.get_or_insert_text("text");
+        let mut txn = doc.transact_mut();
+        root.insert(&mut txn, 0, text);
+        txn.encode_update_v1()
+    }
+
+    fn insert_text(sync_kv: &SyncKv, text: &str) {
+        sync_kv.push_update(DOC_NAME, &text_update(text)).unwrap();
+        sync_kv
+            .flush_doc_with(DOC_NAME, Default::default())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn compacts_deleted_content() {
+        let sync_kv = SyncKv::new(None, "foo", || ()).await.unwrap();
+
+        // A doc edited without garbage collection keeps the content it deleted.
+        let doc = yrs::Doc::with_options(yrs::Options {
+            skip_gc: true,
+            ..Default::default()
+        });
+        let text = doc.get_or_insert_text("text");
+        {
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, &"deleted ".repeat(100));
+            text.insert(&mut txn, 800, "kept");
+        }
+        text.remove_range(&mut doc.transact_mut(), 0, 800);
+        sync_kv.insert_doc(DOC_NAME, &doc.transact()).unwrap();
+        let update = {
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "!");
+            txn.encode_update_v1()
+        };
+        sync_kv.push_update(DOC_NAME, &update).unwrap();
+        sync_kv.dirty.store(false, Ordering::SeqCst);
+
+        let report = sync_kv.compact(true).unwrap();
+        assert!(report.size_after < report.size_before);
+        assert!(!sync_kv.is_dirty());
+
+        let report = sync_kv.compact(false).unwrap();
+        assert!(report.size_after < report.size_before / 2);
+        assert!(sync_kv.is_dirty());
+        assert_eq!(sync_kv.pending_update_count(), 0);
+
+        let doc = yrs::Doc::new();
+        sync_kv.load_doc(DOC_NAME, &mut doc.transact_mut()).unwrap();
+        let text = doc.get_or_insert_text("text").get_string(&doc.transact());
+        assert!(text.ends_with("kept"));
+        assert_eq!(text.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn merges_concurrent_writes() {
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(MemoryStore::default()));
+        let a = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        let b = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+
+        insert_text(&a, "a");
+        a.persist().await.unwrap();
+
+        // `b` was loaded before `a` persisted, so its write conflicts and it has to
+        // merge in `a`'s changes.
+        insert_text(&b, "b");
+        b.persist().await.unwrap();
+
+        let c = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        let doc = yrs::Doc::new();
+        c.load_doc(DOC_NAME, &mut doc.transact_mut()).unwrap();
+        let text = doc.get_or_insert_text("text").get_string(&doc.transact());
+        assert_eq!(text.len(), 2);
+        assert!(text.contains('a') && text.contains('b'));
+    }
+
+    #[tokio::test]
+    async fn persist_new_does_not_merge() {
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(MemoryStore::default()));
+        let a = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        let b = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+
+        insert_text(&a, "a");
+        a.persist_new().await.unwrap();
+
+        insert_text(&b, "b");
+        let err = b.persist_new().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StoreError>(),
+            Some(StoreError::Conflict(_))
+        ));
+
+        let c = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        let doc = yrs::Doc::new();
+        c.load_doc(DOC_NAME, &mut doc.transact_mut()).unwrap();
+        let text = doc.get_or_insert_text("text").get_string(&doc.transact());
+        assert_eq!(text, "a");
+    }
+
+    #[tokio::test]
+    async fn merges_concurrent_writes_into_loaded_doc() {
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(MemoryStore::default()));
+        let a = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        let b = DocWithSyncKv::new(
+            "foo",
+            Some(store.clone()),
+            || (),
+            false,
+            SnapshotFormat::Legacy,
+        )
+        .await
+        .unwrap();
+
+        insert_text(&a, "a");
+        a.persist().await.unwrap();
+
+        b.apply_update(&text_update("b")).unwrap();
+        b.sync_kv().persist().await.unwrap();
+
+        // The doc that `b` serves to its clients has `a`'s changes, not just its data.
+        let awareness = b.awareness();
+        let doc = &awareness.read().unwrap().doc;
+        let text = doc.get_or_insert_text("text").get_string(&doc.transact());
+        assert_eq!(text.len(), 2);
+        assert!(text.contains('a') && text.contains('b'));
+    }
+
+    #[tokio::test]
+    async fn recovers_from_previous_snapshot() {
+        let store = MemoryStore::default();
+
+        {
+            let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+                .await
+                .unwrap();
+            sync_kv.set(b"foo", b"bar");
+            sync_kv.persist().await.unwrap();
+        }
+
+        // Simulate a write that was cut short, after the store kept the previous version.
+        let snapshot = store.data.get("foo/data.ysweet").unwrap().clone();
+        store
+            .data
+            .insert("foo/data.ysweet.prev".to_owned(), snapshot.clone());
+        store.data.insert(
+            "foo/data.ysweet".to_owned(),
+            snapshot[..snapshot.len() - 1].to_vec(),
+        );
+
+        let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap();
+        assert_eq!(sync_kv.get(b"foo"), Some(b"bar".to_vec()));
+
+        // Without a previous version, the corrupt snapshot is an error.
+        store.data.remove("foo/data.ysweet.prev");
+        assert!(
+            SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+                .await
+                .is_err()
+        );
+    }
+
     #[tokio::test]
     async fn only_persists_when_dirty() {
         let store = MemoryStore::default();
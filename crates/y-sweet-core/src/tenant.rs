@@ -0,0 +1,263 @@
+//! Tenants, which let one server host docs for several customers that are kept apart
+//! from each other.
+//!
+//! Each tenant has its own signing key, named by a `KeyId`. Tokens signed with a
+//! tenant's key are prefixed with its key ID, which is how a token's tenant is found,
+//! and the signature ties the token to that tenant: a token can only be verified with
+//! the key of the tenant it names. A tenant's docs are kept under its own store
+//! prefix, so two tenants can use the same doc ID without the docs colliding.
+
+use crate::{
+    api_types::validate_doc_name,
+    auth::{AuthError, Authenticator, KeyId, KeyIdError},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TenantError {
+    #[error("Invalid tenant configuration: {0}")]
+    InvalidConfig(#[from] serde_json::Error),
+    #[error("Invalid key ID for tenant: {0}")]
+    InvalidKeyId(#[from] KeyIdError),
+    #[error("Invalid private key for tenant {0}.")]
+    InvalidPrivateKey(String),
+    #[error("Tenant {0} is configured more than once.")]
+    DuplicateTenant(String),
+    #[error("Store prefix of tenant {0} must be a non-empty path ending in '/'.")]
+    InvalidStorePrefix(String),
+    #[error("Store prefix {0} is used by more than one tenant.")]
+    DuplicateStorePrefix(String),
+}
+
+/// Limits on what a tenant may use. Unset limits are not enforced.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantQuotas {
+    /// How many docs the tenant may have in the store.
+    pub max_docs: Option<usize>,
+    /// How many WebSocket connections to the tenant's docs may be open at once.
+    pub max_connections: Option<usize>,
+}
+
+/// How a tenant is configured, as read from the tenants file.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantConfig {
+    pub key_id: String,
+    pub private_key: String,
+    /// Defaults to `<keyId>/`.
+    pub store_prefix: Option<String>,
+    #[serde(default)]
+    pub quotas: TenantQuotas,
+}
+
+#[derive(Debug)]
+pub struct Tenant {
+    authenticator: Authenticator,
+    store_prefix: String,
+    quotas: TenantQuotas,
+}
+
+impl Tenant {
+    pub fn new(config: TenantConfig) -> Result<Self, TenantError> {
+        let key_id = KeyId::new(config.key_id.clone())?;
+        let authenticator = Authenticator::new(&config.private_key)
+            .map_err(|_: AuthError| TenantError::InvalidPrivateKey(config.key_id.clone()))?
+            .with_key_id(key_id);
+
+        let store_prefix = config
+            .store_prefix
+            .unwrap_or_else(|| format!("{}/", config.key_id));
+        if store_prefix.len() < 2 || !store_prefix.ends_with('/') || store_prefix.starts_with('/') {
+            return Err(TenantError::InvalidStorePrefix(config.key_id));
+        }
+
+        Ok(Self {
+            authenticator,
+            store_prefix,
+            quotas: config.quotas,
+        })
+    }
+
+    /// The tenant's key ID, which also identifies the tenant.
+    pub fn id(&self) -> &str {
+        self.authenticator
+            .key_id()
+            .expect("Tenant authenticators always have a key ID.")
+    }
+
+    pub fn authenticator(&self) -> &Authenticator {
+        &self.authenticator
+    }
+
+    pub fn store_prefix(&self) -> &str {
+        &self.store_prefix
+    }
+
+    pub fn quotas(&self) -> &TenantQuotas {
+        &self.quotas
+    }
+
+    /// The key under which the tenant's doc `doc_id` is kept, in the store and in
+    /// memory, if `doc_id` is a valid doc name. Other IDs, such as ones with `/` or
+    /// `..` in them, could name keys outside of the tenant's store prefix.
+    pub fn doc_key(&self, doc_id: &str) -> Option<String> {
+        validate_doc_name(doc_id).then(|| format!("{}{}", self.store_prefix, doc_id))
+    }
+
+    /// The ID of the tenant's doc that is kept under `doc_key`, if it is one of the
+    /// tenant's docs.
+    pub fn doc_id<'a>(&self, doc_key: &'a str) -> Option<&'a str> {
+        doc_key
+            .strip_prefix(&self.store_prefix)
+            .filter(|doc_id| !doc_id.contains('/'))
+    }
+}
+
+/// The tenants a server hosts, by key ID.
+#[derive(Debug, Default)]
+pub struct Tenants(HashMap<String, Arc<Tenant>>);
+
+impl Tenants {
+    pub fn new(configs: Vec<TenantConfig>) -> Result<Self, TenantError> {
+        let mut tenants: HashMap<String, Arc<Tenant>> = HashMap::new();
+        for config in configs {
+            let tenant = Tenant::new(config)?;
+            if tenants.contains_key(tenant.id()) {
+                return Err(TenantError::DuplicateTenant(tenant.id().to_string()));
+            }
+            if let Some(other) = tenants
+                .values()
+                .find(|other| other.store_prefix == tenant.store_prefix)
+            {
+                return Err(TenantError::DuplicateStorePrefix(
+                    other.store_prefix.clone(),
+                ));
+            }
+            tenants.insert(tenant.id().to_string(), Arc::new(tenant));
+        }
+        Ok(Self(tenants))
+    }
+
+    /// Reads tenants from a JSON array of `TenantConfig`s.
+    pub fn from_json(json: &str) -> Result<Self, TenantError> {
+        Self::new(serde_json::from_str(json)?)
+    }
+
+    pub fn get(&self, key_id: &str) -> Option<&Arc<Tenant>> {
+        self.0.get(key_id)
+    }
+
+    /// The tenant whose doc is kept under `doc_key`, if any.
+    pub fn for_doc_key(&self, doc_key: &str) -> Option<&Arc<Tenant>> {
+        self.0
+            .values()
+            .find(|tenant| tenant.doc_id(doc_key).is_some())
+    }
+
+    /// The tenant whose key ID `token` is prefixed with, if any. The token still has
+    /// to be verified with the tenant's authenticator.
+    pub fn for_token(&self, token: &str) -> Option<&Arc<Tenant>> {
+        let (key_id, _) = token.split_once('.')?;
+        self.get(key_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        self.0.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api_types::Authorization,
+        auth::{ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS},
+    };
+
+    fn config(key_id: &str) -> TenantConfig {
+        TenantConfig {
+            key_id: key_id.to_string(),
+            private_key: Authenticator::gen_key().unwrap().private_key(),
+            store_prefix: None,
+            quotas: TenantQuotas::default(),
+        }
+    }
+
+    #[test]
+    fn tokens_are_bound_to_their_tenant() {
+        let tenants = Tenants::new(vec![config("acme"), config("globex")]).unwrap();
+        let acme = tenants.get("acme").unwrap();
+        let globex = tenants.get("globex").unwrap();
+        assert_eq!(acme.doc_key("doc1").unwrap(), "acme/doc1");
+        assert_eq!(acme.doc_key("../globex/doc1"), None);
+        assert_eq!(acme.doc_key(".."), None);
+        assert_eq!(acme.doc_id("acme/doc1"), Some("doc1"));
+        assert_eq!(acme.doc_id("globex/doc1"), None);
+        assert_eq!(tenants.for_doc_key("globex/doc1").unwrap().id(), "globex");
+        assert!(tenants.for_doc_key("doc1").is_none());
+
+        let token = acme.authenticator().gen_doc_token(
+            "doc1",
+            Authorization::Full,
+            ExpirationTimeEpochMillis(DEFAULT_EXPIRATION_SECONDS * 1000),
+        );
+        assert_eq!(tenants.for_token(&token).unwrap().id(), "acme");
+        assert!(acme
+            .authenticator()
+            .verify_doc_token(&token, "doc1", 0)
+            .is_ok());
+        assert!(matches!(
+            globex.authenticator().verify_doc_token(&token, "doc1", 0),
+            Err(AuthError::KeyMismatch)
+        ));
+
+        // Relabeling the token with another tenant's key ID breaks its signature.
+        let relabeled = token.replacen("acme.", "globex.", 1);
+        assert_eq!(tenants.for_token(&relabeled).unwrap().id(), "globex");
+        assert!(matches!(
+            globex
+                .authenticator()
+                .verify_doc_token(&relabeled, "doc1", 0),
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(matches!(
+            Tenants::new(vec![config("acme"), config("acme")]),
+            Err(TenantError::DuplicateTenant(_))
+        ));
+
+        let mut shared = config("globex");
+        shared.store_prefix = Some("acme/".to_string());
+        assert!(matches!(
+            Tenants::new(vec![config("acme"), shared]),
+            Err(TenantError::DuplicateStorePrefix(_))
+        ));
+
+        let mut no_slash = config("acme");
+        no_slash.store_prefix = Some("acme".to_string());
+        assert!(matches!(
+            Tenants::new(vec![no_slash]),
+            Err(TenantError::InvalidStorePrefix(_))
+        ));
+
+        let json = r#"[{"keyId": "acme", "privateKey": "not base64!", "quotas": {}}]"#;
+        assert!(matches!(
+            Tenants::from_json(json),
+            Err(TenantError::InvalidPrivateKey(_))
+        ));
+    }
+}
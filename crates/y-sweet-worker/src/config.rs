@@ -2,11 +2,17 @@ use serde::{Deserialize, Serialize};
 use std::{str::FromStr, time::Duration};
 use worker::Env;
 use y_sweet_core::auth::KeyId;
-use y_sweet_core::store::s3::S3Config;
+use y_sweet_core::snapshot::{Compression, SnapshotFormat};
+use y_sweet_core::store::encrypted::EncryptionKeys;
+use y_sweet_core::store::s3::{S3Config, S3RetryConfig};
 
 const BUCKET: &str = "Y_SWEET_DATA";
 const BUCKET_KIND: &str = "BUCKET_KIND";
 const AUTH_KEY: &str = "AUTH_KEY";
+const ENCRYPTION_KEYS: &str = "ENCRYPTION_KEYS";
+const ENCRYPTION_ALLOW_PLAINTEXT: &str = "ENCRYPTION_ALLOW_PLAINTEXT";
+const SNAPSHOT_COMPRESSION: &str = "SNAPSHOT_COMPRESSION";
+const SNAPSHOT_CONTAINER: &str = "SNAPSHOT_CONTAINER";
 const S3_ACCESS_KEY_ID: &str = "AWS_ACCESS_KEY_ID";
 const S3_SECRET_ACCESS_KEY: &str = "AWS_SECRET_ACCESS_KEY";
 const S3_SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
@@ -57,6 +63,15 @@ pub struct Configuration {
     pub bucket_prefix: Option<String>,
     pub url_prefix: Option<String>,
     pub timeout_interval: Duration,
+    /// Master keys for encrypting stored documents; see `EncryptionKeys::parse`.
+    #[serde(default)]
+    pub encryption_keys: Option<String>,
+    /// Whether objects written before encryption was enabled can still be read.
+    #[serde(default)]
+    pub encryption_allow_plaintext: bool,
+    /// The format doc snapshots are written in.
+    #[serde(default)]
+    pub snapshot_format: SnapshotFormat,
 }
 
 fn parse_s3_config(env: &Env) -> anyhow::Result<S3Config> {
@@ -89,6 +104,7 @@ fn parse_s3_config(env: &Env) -> anyhow::Result<S3Config> {
             .to_string(),
         bucket_prefix: env.var(S3_BUCKET_PREFIX).ok().map(|t| t.to_string()),
         path_style: false,
+        retry: S3RetryConfig::default(),
     })
 }
 
@@ -109,6 +125,26 @@ impl TryFrom<&Env> for Configuration {
             None
         };
 
+        let encryption_keys = env.var(ENCRYPTION_KEYS).map(|s| s.to_string()).ok();
+        if let Some(keys) = &encryption_keys {
+            // Fail early on invalid keys, rather than on the first store access.
+            EncryptionKeys::parse(keys)?;
+        }
+
+        let encryption_allow_plaintext = env
+            .var(ENCRYPTION_ALLOW_PLAINTEXT)
+            .is_ok_and(|value| value.to_string() == "true");
+
+        let snapshot_compression = match env.var(SNAPSHOT_COMPRESSION) {
+            Ok(compression) => {
+                Compression::from_str(&compression.to_string()).map_err(|e| anyhow::anyhow!(e))?
+            }
+            Err(_) => Compression::None,
+        };
+        let snapshot_container = env
+            .var(SNAPSHOT_CONTAINER)
+            .is_ok_and(|value| value.to_string() == "true");
+
         Ok(Self {
             auth_key,
             auth_key_id: None,
@@ -117,6 +153,9 @@ impl TryFrom<&Env> for Configuration {
             bucket_prefix: env.var(S3_BUCKET_PREFIX).map(|s| s.to_string()).ok(),
             url_prefix: None,
             timeout_interval,
+            encryption_keys,
+            encryption_allow_plaintext,
+            snapshot_format: SnapshotFormat::new(snapshot_container, snapshot_compression),
         })
     }
 }
@@ -53,6 +53,7 @@ impl YServe {
                     });
                 },
                 false,
+                config.snapshot_format,
             )
             .await
             .map_err(|e| format!("Error creating doc: {:?}", e))?;
@@ -33,6 +33,8 @@ pub enum Error {
     CouldNotForwardRequest(worker::Error),
     #[error("Error creating doc.")]
     ErrorCreatingDoc(String),
+    #[error("Creating a document with initial content is not supported.")]
+    InitialContentNotSupported,
 }
 
 impl Error {
@@ -52,6 +54,7 @@ impl Error {
             Self::CouldNotConstructRequest => 500,
             Self::CouldNotForwardRequest(_) => 500,
             Self::ErrorCreatingDoc(_) => 500,
+            Self::InitialContentNotSupported => 501,
         }
     }
 }
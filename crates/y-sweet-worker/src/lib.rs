@@ -9,10 +9,12 @@ use worker::{event, Env};
 use worker::{Date, Method, Request, Response, ResponseBody, Result, RouteContext, Router, Url};
 use y_sweet_core::{
     api_types::{
-        validate_doc_name, AuthDocRequest, Authorization, ClientToken, DocCreationRequest,
-        NewDocResponse,
+        validate_doc_name, validate_doc_pattern, AuthDocRequest, Authorization, ClientToken,
+        DocCreationRequest, NewDocResponse,
+    },
+    auth::{
+        doc_pattern_matches, Authenticator, ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS,
     },
-    auth::{Authenticator, ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS},
     doc_sync::DocWithSyncKv,
     store::StoreError,
 };
@@ -126,6 +128,10 @@ async fn new_doc(
         .await
         .map_err(|_| Error::BadRequest)?;
 
+    if body.initial_update.is_some() || body.initial_content.is_some() {
+        return Err(Error::InitialContentNotSupported);
+    }
+
     let doc_id = body.doc_id.unwrap_or_else(|| nanoid::nanoid!());
 
     if !validate_doc_name(&doc_id) {
@@ -219,14 +225,22 @@ async fn auth_doc(
         return Err(Error::BadRequest);
     }
 
+    if let Some(doc_pattern) = &body.doc_pattern {
+        if !validate_doc_pattern(doc_pattern) || !doc_pattern_matches(doc_pattern, &doc_id) {
+            return Err(Error::BadRequest);
+        }
+    }
+
     let valid_time_seconds = body.valid_for_seconds.unwrap_or(DEFAULT_EXPIRATION_SECONDS);
     let expiration_time =
         ExpirationTimeEpochMillis(get_time_millis_since_epoch() + valid_time_seconds * 1000);
 
-    let token = ctx
-        .data
-        .auth()?
-        .map(|auth| auth.gen_doc_token(&doc_id, body.authorization, expiration_time));
+    let token = ctx.data.auth()?.map(|auth| match &body.doc_pattern {
+        Some(doc_pattern) => {
+            auth.gen_doc_pattern_token(doc_pattern, body.authorization, None, expiration_time)
+        }
+        None => auth.gen_doc_token(&doc_id, body.authorization, expiration_time),
+    });
 
     let url = if let Some(url_prefix) = &ctx.data.config.url_prefix {
         let mut parsed = Url::parse(url_prefix).map_err(|_| Error::ConfigurationError {
@@ -1,6 +1,9 @@
 use async_trait::async_trait;
+use js_sys::{Object, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
 use worker::Bucket;
-use y_sweet_core::store::{Result, Store, StoreError};
+use y_sweet_core::store::{ExpectedVersion, Result, Store, StoreError};
 pub struct R2Store {
     bucket: Bucket,
     path_prefix: Option<String>,
@@ -30,6 +33,10 @@ impl Store for R2Store {
     }
 
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key).await?.map(|(bytes, _)| bytes))
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
         let object = self
             .bucket
             .get(self.prefixed_key(key))
@@ -45,7 +52,7 @@ impl Store for R2Store {
                 .map_err(|e| {
                     StoreError::ConnectionError(format!("Failed to get object bytes {e}"))
                 })?;
-            Ok(Some(bytes))
+            Ok(Some((bytes, Some(object.etag()))))
         } else {
             Ok(None)
         }
@@ -60,6 +67,51 @@ impl Store for R2Store {
         Ok(())
     }
 
+    /// `worker::Bucket::put` does not expose R2's `onlyIf` option, so this calls the
+    /// binding directly. R2 resolves a put whose condition fails with `null`.
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        let condition = Object::new();
+        match expected {
+            ExpectedVersion::Absent => {
+                set_property(&condition, "etagDoesNotMatch", &"*".into())?;
+            }
+            ExpectedVersion::Version(etag) => {
+                set_property(&condition, "etagMatches", &etag.into())?;
+            }
+            ExpectedVersion::Any => {}
+        }
+        let options = Object::new();
+        set_property(&options, "onlyIf", &condition)?;
+
+        let bucket: &worker::worker_sys::R2Bucket = self.bucket.as_ref().unchecked_ref();
+        let promise = bucket
+            .put(
+                self.prefixed_key(key),
+                Uint8Array::from(value.as_slice()).into(),
+                options.into(),
+            )
+            .map_err(|e| StoreError::ConnectionError(format!("Failed to put object {e:?}")))?;
+        let object = JsFuture::from(promise)
+            .await
+            .map_err(|e| StoreError::ConnectionError(format!("Failed to put object {e:?}")))?;
+
+        if object.is_null() {
+            return Err(StoreError::Conflict(format!(
+                "Object does not match {:?}.",
+                expected
+            )));
+        }
+        let etag = Reflect::get(&object, &"etag".into())
+            .ok()
+            .and_then(|etag| etag.as_string());
+        Ok(etag)
+    }
+
     async fn remove(&self, _key: &str) -> Result<()> {
         todo!()
     }
@@ -71,4 +123,37 @@ impl Store for R2Store {
             .map(|r| r.is_some())
             .map_err(|e| StoreError::ConnectionError(format!("Failed to head object {e}")))
     }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let path_prefix = self.prefixed_key("");
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut request = self.bucket.list().prefix(self.prefixed_key(prefix));
+            if let Some(cursor) = cursor {
+                request = request.cursor(cursor);
+            }
+            let page = request
+                .execute()
+                .await
+                .map_err(|e| StoreError::ConnectionError(format!("Failed to list objects {e}")))?;
+            keys.extend(page.objects().into_iter().filter_map(|object| {
+                object
+                    .key()
+                    .strip_prefix(path_prefix.as_str())
+                    .map(str::to_owned)
+            }));
+
+            if !page.truncated() {
+                return Ok(keys);
+            }
+            cursor = page.cursor();
+        }
+    }
+}
+
+fn set_property(target: &Object, key: &str, value: &JsValue) -> Result<()> {
+    Reflect::set(target, &key.into(), value)
+        .map(|_| ())
+        .map_err(|e| StoreError::ConnectionError(format!("Failed to build put options {e:?}")))
 }
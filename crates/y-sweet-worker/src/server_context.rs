@@ -1,7 +1,12 @@
 use crate::{config::Configuration, error::Error, r2_store::R2Store};
 use std::sync::Arc;
 use worker::{Env, Request};
-use y_sweet_core::{auth::Authenticator, store::s3::S3Store, store::Store};
+use y_sweet_core::{
+    auth::Authenticator,
+    store::encrypted::{EncryptedStore, EncryptionKeys},
+    store::s3::S3Store,
+    store::Store,
+};
 
 const CONTEXT_HEADER: &str = "X-Y-Sweet-Context";
 const ROUTE_HEADER: &str = "X-Y-Sweet-Route";
@@ -21,6 +26,15 @@ impl ServerContext {
         } else {
             Box::new(R2Store::new(bucket, config.bucket_prefix.clone()))
         };
+        let store: Box<dyn Store> = if let Some(keys) = config.encryption_keys.as_ref() {
+            let keys = EncryptionKeys::parse(keys).expect("Keys are validated by Configuration");
+            Box::new(
+                EncryptedStore::new(store, keys)
+                    .with_plaintext_reads(config.encryption_allow_plaintext),
+            )
+        } else {
+            store
+        };
         #[allow(clippy::arc_with_non_send_sync)] // Arc required for compatibility with core.
         let store: Arc<Box<dyn Store>> = Arc::new(store);
 
@@ -0,0 +1,203 @@
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+};
+use y_sweet_core::api_types::Authorization;
+
+/// Identifies who is accessing which document.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditSubject {
+    pub doc_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+impl AuditSubject {
+    pub fn new(doc_id: &str, key_id: Option<&str>, user_id: Option<String>) -> Self {
+        Self {
+            doc_id: doc_id.to_string(),
+            key_id: key_id.map(|k| k.to_string()),
+            user_id,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEventKind {
+    TokenVerified { authorization: Authorization },
+    TokenRejected { reason: String },
+    ConnectionOpened { authorization: Authorization },
+    ConnectionClosed { authorization: Authorization },
+    HttpUpdate { size: usize },
+}
+
+#[derive(Serialize)]
+pub struct AuditEvent<'a> {
+    pub timestamp_millis: u64,
+    #[serde(flatten)]
+    pub subject: &'a AuditSubject,
+    #[serde(flatten)]
+    pub kind: AuditEventKind,
+}
+
+/// Destination for audit events. Audit events are kept separate from the
+/// `tracing` debug logs.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: &AuditEvent);
+}
+
+/// Appends each audit event to a file as a line of JSON.
+///
+/// Events are written by a dedicated thread, so that recording one never blocks the
+/// async handler that records it on file I/O.
+pub struct JsonLinesAuditSink {
+    sender: Sender<Vec<u8>>,
+}
+
+impl JsonLinesAuditSink {
+    pub fn new(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = channel();
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || write_lines(file, receiver))?;
+        Ok(Self { sender })
+    }
+}
+
+/// Writes lines from `receiver` to `file` until every sender is dropped, flushing
+/// whenever no more lines are queued.
+fn write_lines(file: File, receiver: Receiver<Vec<u8>>) {
+    let mut writer = BufWriter::new(file);
+    while let Ok(line) = receiver.recv() {
+        let result = std::iter::once(line)
+            .chain(receiver.try_iter())
+            .try_for_each(|line| writer.write_all(&line))
+            .and_then(|()| writer.flush());
+        if let Err(e) = result {
+            tracing::error!(?e, "Failed to write audit event");
+        }
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!(?e, "Failed to serialize audit event");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if self.sender.send(line).is_err() {
+            tracing::error!("Failed to write audit event: the audit log writer has stopped");
+        }
+    }
+}
+
+/// Cheaply-cloneable handle to an optional audit sink. Recording an event is a
+/// no-op if no sink is configured.
+#[derive(Clone, Default)]
+pub struct AuditLog(Option<Arc<dyn AuditSink>>);
+
+impl AuditLog {
+    pub fn new(sink: Arc<dyn AuditSink>) -> Self {
+        Self(Some(sink))
+    }
+
+    pub fn record(&self, subject: &AuditSubject, kind: AuditEventKind) {
+        if let Some(sink) = &self.0 {
+            let timestamp_millis = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+
+            sink.record(&AuditEvent {
+                timestamp_millis,
+                subject,
+                kind,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{sync::Mutex, time::Duration};
+
+    #[derive(Default)]
+    struct MemorySink(Mutex<Vec<String>>);
+
+    impl AuditSink for MemorySink {
+        fn record(&self, event: &AuditEvent) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(serde_json::to_string(event).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_event_format() {
+        let sink = Arc::new(MemorySink::default());
+        let log = AuditLog::new(sink.clone());
+        let subject = AuditSubject::new("doc123", Some("key1"), None);
+
+        log.record(
+            &subject,
+            AuditEventKind::ConnectionOpened {
+                authorization: Authorization::ReadOnly,
+            },
+        );
+
+        let events = sink.0.lock().unwrap();
+        let event: serde_json::Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(event["event"], "connection_opened");
+        assert_eq!(event["doc_id"], "doc123");
+        assert_eq!(event["key_id"], "key1");
+        assert_eq!(event["authorization"], "read-only");
+        assert!(event.get("user_id").is_none());
+    }
+
+    #[test]
+    fn test_json_lines_sink() {
+        let path = std::env::temp_dir().join(format!("y-sweet-audit-{}.jsonl", nanoid::nanoid!()));
+        let log = AuditLog::new(Arc::new(JsonLinesAuditSink::new(&path).unwrap()));
+        for doc_id in ["doc1", "doc2"] {
+            log.record(
+                &AuditSubject::new(doc_id, None, Some("user1".to_string())),
+                AuditEventKind::HttpUpdate { size: 3 },
+            );
+        }
+
+        // Events are written in the background, so wait for them to show up.
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            let contents = std::fs::read_to_string(&path).unwrap();
+            lines = contents.lines().map(str::to_owned).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines.len(), 2);
+        let event: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(event["event"], "http_update");
+        assert_eq!(event["doc_id"], "doc2");
+        assert_eq!(event["user_id"], "user1");
+    }
+}
@@ -0,0 +1,239 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use reqwest::{header::AUTHORIZATION, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use url::Url;
+use y_sweet_core::{
+    api_types::Authorization,
+    authorizer::{Authorizer, AuthorizerError},
+};
+
+pub const DEFAULT_AUTHORIZER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Expired entries are only dropped when the cache grows past this many entries.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+// If the cache is still full once expired entries are dropped, this many of the oldest
+// entries are dropped, so that the cache does not have to be trimmed on every insert.
+const CACHE_EVICTION_BATCH: usize = MAX_CACHE_ENTRIES / 10;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizeRequest<'a> {
+    doc_id: &'a str,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeResponse {
+    authorization: Authorization,
+}
+
+/// Asks an HTTP service whether a token gives access to a doc.
+///
+/// The service is sent a POST request with a JSON body of the form
+/// `{"docId": "..."}`, and the token as a bearer token in the `Authorization` header.
+/// It grants access by responding with `{"authorization": "full"}` or
+/// `{"authorization": "read-only"}`, and denies it by responding with 401, 403 or 404.
+/// Grants are cached for `cache_ttl`; denials are not cached.
+pub struct HttpAuthorizer {
+    client: reqwest::Client,
+    url: Url,
+    cache_ttl: Duration,
+    cache: DashMap<(String, Option<String>), (Authorization, Instant)>,
+}
+
+impl HttpAuthorizer {
+    pub fn new(url: Url, cache_ttl: Duration, timeout: Duration) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self {
+            client,
+            url,
+            cache_ttl,
+            cache: DashMap::new(),
+        })
+    }
+
+    fn cached(&self, key: &(String, Option<String>)) -> Option<Authorization> {
+        let entry = self.cache.get(key)?;
+        let (authorization, expires_at) = *entry;
+        (Instant::now() < expires_at).then_some(authorization)
+    }
+
+    fn cache(&self, key: (String, Option<String>), authorization: Authorization) {
+        if self.cache.len() >= MAX_CACHE_ENTRIES {
+            let now = Instant::now();
+            self.cache.retain(|_, (_, expires_at)| now < *expires_at);
+            if self.cache.len() >= MAX_CACHE_ENTRIES {
+                // All entries live for the same TTL, so the oldest expire first.
+                let mut expiries: Vec<Instant> =
+                    self.cache.iter().map(|entry| entry.value().1).collect();
+                let (_, cutoff, _) = expiries.select_nth_unstable(CACHE_EVICTION_BATCH - 1);
+                let cutoff = *cutoff;
+                self.cache.retain(|_, (_, expires_at)| *expires_at > cutoff);
+            }
+        }
+        self.cache
+            .insert(key, (authorization, Instant::now() + self.cache_ttl));
+    }
+
+    async fn request(
+        &self,
+        doc_id: &str,
+        token: Option<&str>,
+    ) -> Result<Authorization, AuthorizerError> {
+        let body = serde_json::to_vec(&AuthorizeRequest { doc_id })
+            .expect("Serializing the request should not fail.");
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AuthorizerError::Unavailable(e.to_string()))?;
+        match response.status() {
+            StatusCode::OK => {
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| AuthorizerError::Unavailable(e.to_string()))?;
+                let response: AuthorizeResponse = serde_json::from_slice(&body).map_err(|e| {
+                    AuthorizerError::Unavailable(format!("Invalid response: {}", e))
+                })?;
+                Ok(response.authorization)
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => Err(
+                AuthorizerError::Denied(format!("Authorizer responded {}.", response.status())),
+            ),
+            status => Err(AuthorizerError::Unavailable(format!(
+                "Authorizer responded {}.",
+                status
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl Authorizer for HttpAuthorizer {
+    async fn authorize(
+        &self,
+        doc_id: &str,
+        token: Option<&str>,
+    ) -> Result<Authorization, AuthorizerError> {
+        let key = (doc_id.to_string(), token.map(str::to_owned));
+        if let Some(authorization) = self.cached(&key) {
+            return Ok(authorization);
+        }
+
+        let authorization = self.request(doc_id, token).await?;
+        self.cache(key, authorization);
+        Ok(authorization)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Json, Router};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn test_http_authorizer() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/authorize",
+            post({
+                let calls = calls.clone();
+                move |headers: HeaderMap, Json(body): Json<serde_json::Value>| async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    let token = headers
+                        .get("authorization")
+                        .and_then(|value| value.to_str().ok());
+                    match (token, body["docId"].as_str()) {
+                        (Some("Bearer writer"), Some("doc1")) => {
+                            Ok(Json(serde_json::json!({"authorization": "full"})))
+                        }
+                        (Some("Bearer reader"), Some("doc1")) => {
+                            Ok(Json(serde_json::json!({"authorization": "read-only"})))
+                        }
+                        _ => Err(StatusCode::FORBIDDEN),
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let url = Url::parse(&format!("http://{}/authorize", addr)).unwrap();
+        let authorizer =
+            HttpAuthorizer::new(url, Duration::from_secs(60), DEFAULT_AUTHORIZER_TIMEOUT).unwrap();
+
+        assert!(matches!(
+            authorizer.authorize("doc1", Some("writer")).await,
+            Ok(Authorization::Full)
+        ));
+        assert!(matches!(
+            authorizer.authorize("doc1", Some("reader")).await,
+            Ok(Authorization::ReadOnly)
+        ));
+        assert!(matches!(
+            authorizer.authorize("doc2", Some("writer")).await,
+            Err(AuthorizerError::Denied(_))
+        ));
+        assert!(matches!(
+            authorizer.authorize("doc1", None).await,
+            Err(AuthorizerError::Denied(_))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // Grants are served from the cache; denials are asked again.
+        assert!(matches!(
+            authorizer.authorize("doc1", Some("writer")).await,
+            Ok(Authorization::Full)
+        ));
+        assert!(authorizer.authorize("doc2", Some("writer")).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+        let unreachable = HttpAuthorizer::new(
+            Url::parse("http://127.0.0.1:1/authorize").unwrap(),
+            Duration::from_secs(60),
+            DEFAULT_AUTHORIZER_TIMEOUT,
+        )
+        .unwrap();
+        assert!(matches!(
+            unreachable.authorize("doc1", Some("writer")).await,
+            Err(AuthorizerError::Unavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_cache_evicts_oldest_entries() {
+        let authorizer = HttpAuthorizer::new(
+            Url::parse("http://127.0.0.1:1/authorize").unwrap(),
+            Duration::from_secs(60),
+            DEFAULT_AUTHORIZER_TIMEOUT,
+        )
+        .unwrap();
+        let key = |i: usize| (format!("doc{}", i), None);
+        for i in 0..=MAX_CACHE_ENTRIES {
+            authorizer.cache(key(i), Authorization::Full);
+        }
+
+        // Only the oldest entries were dropped to make room.
+        assert!(authorizer.cache.len() <= MAX_CACHE_ENTRIES - CACHE_EVICTION_BATCH + 1);
+        assert!(authorizer.cache.len() > MAX_CACHE_ENTRIES / 2);
+        assert!(authorizer.cached(&key(0)).is_none());
+        assert!(authorizer.cached(&key(MAX_CACHE_ENTRIES - 1)).is_some());
+        assert!(authorizer.cached(&key(MAX_CACHE_ENTRIES)).is_some());
+    }
+}
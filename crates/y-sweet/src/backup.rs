@@ -0,0 +1,378 @@
+//! Portable backups of a store, as a tar archive of Yjs updates.
+//!
+//! The archive holds one entry per document at `docs/<doc_id>.bin`, containing the
+//! document's full state as a Yjs v1 update, followed by a `manifest.json` entry that
+//! lists the documents along with their metadata. Since the documents are standard Yjs
+//! updates, they can also be read without Y-Sweet.
+
+use crate::convert::{convert, load_doc};
+use anyhow::{anyhow, bail, Context, Result};
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    sync::Arc,
+};
+use y_sweet_core::{
+    api_types::{validate_doc_name, DocMetadata},
+    store::{list_doc_ids, Store, DOC_METADATA_SUFFIX},
+};
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    ReadTxn, StateVector, Transact,
+};
+
+const MANIFEST_PATH: &str = "manifest.json";
+const DOCS_DIR: &str = "docs/";
+const DOC_EXTENSION: &str = ".bin";
+const MANIFEST_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub format_version: u32,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub docs: Vec<ManifestDoc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestDoc {
+    pub doc_id: String,
+    /// Path of the document's update within the archive.
+    pub path: String,
+    /// Size of the update in bytes.
+    pub size: u64,
+    /// The document's state vector, v1-encoded and then base64-encoded.
+    pub state_vector: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocMetadata>,
+}
+
+/// Writes every document in `store` to a tar archive at `out`, calling `on_progress`
+/// with the position of the document, the number of documents and its ID after each
+/// one is written.
+pub async fn export<F>(
+    store: &Arc<Box<dyn Store>>,
+    out: &Path,
+    mut on_progress: F,
+) -> Result<Manifest>
+where
+    F: FnMut(usize, usize, &str),
+{
+    let doc_ids = list_doc_ids(store.as_ref().as_ref())
+        .await
+        .context("Failed to list docs")?;
+    let file = File::create(out).with_context(|| format!("Failed to create {}", out.display()))?;
+    let mut archive = tar::Builder::new(file);
+    let created_at = now_millis();
+
+    let mut docs = Vec::with_capacity(doc_ids.len());
+    for (index, doc_id) in doc_ids.iter().enumerate() {
+        let doc = load_doc(store, doc_id)
+            .await
+            .with_context(|| format!("Failed to load doc {}", doc_id))?;
+        let (update, state_vector) = {
+            let txn = doc.transact();
+            (
+                txn.encode_state_as_update_v1(&StateVector::default()),
+                txn.state_vector(),
+            )
+        };
+
+        let metadata = match store
+            .get(&format!("{}{}", doc_id, DOC_METADATA_SUFFIX))
+            .await?
+        {
+            Some(metadata) => Some(
+                serde_json::from_slice(&metadata)
+                    .with_context(|| format!("Failed to read metadata of doc {}", doc_id))?,
+            ),
+            None => None,
+        };
+
+        let path = doc_path(doc_id);
+        append(&mut archive, &path, &update, created_at)?;
+        docs.push(ManifestDoc {
+            doc_id: doc_id.clone(),
+            path,
+            size: update.len() as u64,
+            state_vector: BASE64.encode(&state_vector.encode_v1()),
+            metadata,
+        });
+        on_progress(index + 1, doc_ids.len(), doc_id);
+    }
+
+    let manifest = Manifest {
+        format_version: MANIFEST_FORMAT_VERSION,
+        created_at,
+        docs,
+    };
+    append(
+        &mut archive,
+        MANIFEST_PATH,
+        &serde_json::to_vec_pretty(&manifest)?,
+        created_at,
+    )?;
+    archive.into_inner()?.sync_all()?;
+    Ok(manifest)
+}
+
+/// Restores the documents in the archive at `archive` into `store`. Documents that
+/// already exist in the store are merged with the backup, as with any other Yjs
+/// update, and their metadata is replaced with the backup's. Each restored document is
+/// checked against the state vector in the manifest.
+///
+/// The archive is read twice: once to check the manifest and that the archive holds
+/// exactly the documents it lists, and once to restore them, so that nothing is written
+/// for an archive that would be rejected.
+pub async fn import<F>(
+    store: &Arc<Box<dyn Store>>,
+    archive: &Path,
+    mut on_progress: F,
+) -> Result<Manifest>
+where
+    F: FnMut(&str),
+{
+    let manifest = read_manifest(archive)?;
+    let docs: BTreeMap<&str, &ManifestDoc> = manifest
+        .docs
+        .iter()
+        .map(|doc| (doc.path.as_str(), doc))
+        .collect();
+
+    for entry in open_archive(archive)?.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let Some(doc) = docs.get(path.as_str()) else {
+            continue;
+        };
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        convert(store.clone(), &contents, &doc.doc_id)
+            .await
+            .with_context(|| format!("Failed to restore doc {}", doc.doc_id))?;
+        on_progress(&doc.doc_id);
+    }
+
+    for doc in &manifest.docs {
+        let expected = StateVector::decode_v1(&BASE64.decode(doc.state_vector.as_bytes())?)?;
+        let restored = load_doc(store, &doc.doc_id)
+            .await?
+            .transact()
+            .state_vector();
+        if expected
+            .iter()
+            .any(|(client, clock)| restored.get(client) < *clock)
+        {
+            bail!(
+                "Restored doc {} is missing changes from the backup.",
+                doc.doc_id
+            );
+        }
+
+        if let Some(metadata) = &doc.metadata {
+            let key = format!("{}{}", doc.doc_id, DOC_METADATA_SUFFIX);
+            store.set(&key, serde_json::to_vec(metadata)?).await?;
+        }
+    }
+    Ok(manifest)
+}
+
+/// Reads and checks the manifest of the archive at `path`: its format version, that
+/// every doc ID is valid and stored at the path `export` would use, and that the
+/// archive holds exactly the listed documents, at the listed sizes.
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    let mut manifest = None;
+    let mut sizes = BTreeMap::new();
+    for entry in open_archive(path)?.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if path == MANIFEST_PATH {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            manifest = Some(serde_json::from_slice::<Manifest>(&contents)?);
+        } else if path.starts_with(DOCS_DIR) {
+            sizes.insert(path, entry.size());
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| anyhow!("Archive does not contain a manifest."))?;
+    if manifest.format_version != MANIFEST_FORMAT_VERSION {
+        bail!(
+            "Unsupported manifest format version {}.",
+            manifest.format_version
+        );
+    }
+    for doc in &manifest.docs {
+        if !validate_doc_name(&doc.doc_id) {
+            bail!("Invalid doc ID {:?} in the manifest.", doc.doc_id);
+        }
+        if doc.path != doc_path(&doc.doc_id) {
+            bail!("Doc {} has unexpected path {:?}.", doc.doc_id, doc.path);
+        }
+        match sizes.remove(&doc.path) {
+            Some(size) if size == doc.size => {}
+            Some(_) => bail!("Size of {} does not match the manifest.", doc.path),
+            None => bail!("Archive is missing {}.", doc.path),
+        }
+    }
+    if let Some(path) = sizes.keys().next() {
+        bail!("Archive contains {}, which is not in the manifest.", path);
+    }
+    Ok(manifest)
+}
+
+fn open_archive(path: &Path) -> Result<tar::Archive<File>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(tar::Archive::new(file))
+}
+
+fn doc_path(doc_id: &str) -> String {
+    format!("{}{}{}", DOCS_DIR, doc_id, DOC_EXTENSION)
+}
+
+fn append<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+    mtime_millis: u64,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime_millis / 1000);
+    archive.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use std::path::PathBuf;
+    use yrs::{GetString, Text, WriteTxn};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("y-sweet-backup-{}", nanoid::nanoid!()))
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let (from_dir, to_dir, archive_dir) = (temp_dir(), temp_dir(), temp_dir());
+        let from: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(from_dir.clone()).unwrap()));
+        let to: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(to_dir.clone()).unwrap()));
+        std::fs::create_dir_all(&archive_dir).unwrap();
+        let archive = archive_dir.join("backup.tar");
+
+        for (doc_id, text) in [("doc1", "hello"), ("doc2", "world")] {
+            let doc = yrs::Doc::new();
+            let update = {
+                let mut txn = doc.transact_mut();
+                txn.get_or_insert_text("text").insert(&mut txn, 0, text);
+                txn.encode_update_v1()
+            };
+            convert(from.clone(), &update, doc_id).await.unwrap();
+        }
+        let metadata = DocMetadata {
+            title: Some("Greeting".to_string()),
+            ..Default::default()
+        };
+        let metadata_key = format!("doc1{}", DOC_METADATA_SUFFIX);
+        from.set(&metadata_key, serde_json::to_vec(&metadata).unwrap())
+            .await
+            .unwrap();
+
+        let manifest = export(&from, &archive, |_, _, _| ()).await.unwrap();
+        assert_eq!(manifest.docs.len(), 2);
+        assert_eq!(manifest.docs[0].path, "docs/doc1.bin");
+        assert_eq!(manifest.docs[0].metadata, Some(metadata.clone()));
+        assert_eq!(manifest.docs[1].metadata, None);
+
+        let mut restored = Vec::new();
+        import(&to, &archive, |doc_id| restored.push(doc_id.to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(restored, vec!["doc1", "doc2"]);
+
+        let doc = load_doc(&to, "doc2").await.unwrap();
+        let text = doc.get_or_insert_text("text").get_string(&doc.transact());
+        assert_eq!(text, "world");
+        let restored_metadata = to.get(&metadata_key).await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<DocMetadata>(&restored_metadata).unwrap(),
+            metadata
+        );
+
+        for dir in [from_dir, to_dir, archive_dir] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_rejects_invalid_archives() {
+        let (to_dir, archive_dir) = (temp_dir(), temp_dir());
+        let to: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(to_dir.clone()).unwrap()));
+        std::fs::create_dir_all(&archive_dir).unwrap();
+
+        let update = {
+            let doc = yrs::Doc::new();
+            let mut txn = doc.transact_mut();
+            txn.get_or_insert_text("text").insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+        let write_archive = |name: &str, doc_id: &str, format_version: u32| {
+            let path = archive_dir.join(name);
+            let mut archive = tar::Builder::new(File::create(&path).unwrap());
+            // `append` refuses paths that leave the archive, so write the name directly.
+            let doc_path = format!("{}{}{}", DOCS_DIR, doc_id, DOC_EXTENSION);
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..doc_path.len()].copy_from_slice(doc_path.as_bytes());
+            header.set_size(update.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append(&header, update.as_slice()).unwrap();
+            let manifest = Manifest {
+                format_version,
+                created_at: 0,
+                docs: vec![ManifestDoc {
+                    doc_id: doc_id.to_owned(),
+                    path: doc_path,
+                    size: update.len() as u64,
+                    state_vector: BASE64.encode(&StateVector::default().encode_v1()),
+                    metadata: None,
+                }],
+            };
+            let manifest = serde_json::to_vec(&manifest).unwrap();
+            append(&mut archive, MANIFEST_PATH, &manifest, 0).unwrap();
+            archive.into_inner().unwrap();
+            path
+        };
+
+        // A doc ID that would escape the store is rejected before anything is written.
+        let traversal = write_archive("traversal.tar", "../escaped", MANIFEST_FORMAT_VERSION);
+        assert!(import(&to, &traversal, |_| ()).await.is_err());
+        let unsupported = write_archive("unsupported.tar", "doc1", MANIFEST_FORMAT_VERSION + 1);
+        assert!(import(&to, &unsupported, |_| ()).await.is_err());
+        assert_eq!(std::fs::read_dir(&to_dir).unwrap().count(), 0);
+        assert!(!to_dir.parent().unwrap().join("escaped").exists());
+
+        for dir in [to_dir, archive_dir] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
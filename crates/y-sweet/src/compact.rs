@@ -0,0 +1,40 @@
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+use y_sweet_core::{
+    api_types::CompactionReport,
+    snapshot::SnapshotFormat,
+    store::{Store, DOC_SNAPSHOT_SUFFIX},
+    sync_kv::SyncKv,
+};
+
+/// Compacts a doc in `store` (see `SyncKv::compact`) and, unless this is a dry run,
+/// writes it back in `format`.
+///
+/// The doc must not be loaded by a server while this runs. The server would merge the
+/// compacted snapshot into its uncompacted state when it next persists the doc, which
+/// undoes the compaction; `Server::compact_doc` compacts a loaded doc.
+pub async fn compact_doc(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    dry_run: bool,
+    format: SnapshotFormat,
+) -> Result<CompactionReport> {
+    if !store
+        .exists(&format!("{}{}", doc_id, DOC_SNAPSHOT_SUFFIX))
+        .await?
+    {
+        bail!("Doc {} does not exist in the store.", doc_id);
+    }
+
+    let sync_kv = SyncKv::new(Some(store.clone()), doc_id, || ())
+        .await?
+        .with_format(format);
+    let report = sync_kv.compact(dry_run)?;
+    if !dry_run {
+        sync_kv
+            .persist()
+            .await
+            .map_err(|e| anyhow!("Failed to persist: {:?}", e))?;
+    }
+    Ok(report)
+}
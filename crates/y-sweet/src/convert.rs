@@ -1,13 +1,12 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::sync::Arc;
 use y_sweet_core::{doc_connection::DOC_NAME, store::Store, sync_kv::SyncKv};
+use yrs::Transact;
 use yrs_kvstore::DocOps;
 
 /// Convert a Yjs document (encoded as a v1 update) to a .ysweet store.
-pub async fn convert(store: Box<dyn Store>, doc_as_update: &[u8], doc_id: &str) -> Result<()> {
-    let store = Some(Arc::new(store));
-
-    let sync_kv = SyncKv::new(store, doc_id, || ()).await?;
+pub async fn convert(store: Arc<Box<dyn Store>>, doc_as_update: &[u8], doc_id: &str) -> Result<()> {
+    let sync_kv = SyncKv::new(Some(store), doc_id, || ()).await?;
 
     let sync_kv = Arc::new(sync_kv);
 
@@ -26,3 +25,14 @@ pub async fn convert(store: Box<dyn Store>, doc_as_update: &[u8], doc_id: &str)
 
     Ok(())
 }
+
+/// Load a document from a .ysweet store, the reverse of `convert`. A document that
+/// does not exist in the store is loaded as an empty document.
+pub async fn load_doc(store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<yrs::Doc> {
+    let sync_kv = SyncKv::new(Some(store.clone()), doc_id, || ()).await?;
+    let doc = yrs::Doc::new();
+    sync_kv
+        .load_doc(DOC_NAME, &mut doc.transact_mut())
+        .map_err(|_| anyhow!("Failed to load doc"))?;
+    Ok(doc)
+}
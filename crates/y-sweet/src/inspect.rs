@@ -0,0 +1,404 @@
+//! Inspection of stored documents, for debugging.
+//!
+//! A snapshot holds the key-value map that `yrs_kvstore` keeps for a document. Its keys
+//! follow this layout (see `yrs_kvstore::keys`):
+//!
+//! ```text
+//! 00{doc_name}0          - OID of the named document
+//! 01{oid:4}0             - document state
+//! 01{oid:4}1             - state vector
+//! 01{oid:4}2{clock:4}0   - update that has not been merged into the state yet
+//! 01{oid:4}3{name}0      - metadata
+//! ```
+
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+};
+use y_sweet_core::{
+    doc_connection::DOC_NAME,
+    snapshot::{self, SnapshotInfo},
+    store::{Store, DOC_SNAPSHOT_SUFFIX},
+    sync_kv::SyncKv,
+};
+use yrs::{
+    branch::{Branch, BranchPtr},
+    types::ToJson,
+    updates::decoder::Decode,
+    ArrayRef, GetString, Map, MapRef, Out, ReadTxn, StateVector, TextRef, Transact,
+};
+use yrs_kvstore::{
+    keys::{
+        KEYSPACE_DOC, KEYSPACE_OID, SUB_DOC, SUB_META, SUB_STATE_VEC, SUB_UPDATE, TERMINATOR, V1,
+    },
+    DocOps,
+};
+
+/// What a key in a snapshot holds.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    Oid { doc_name: String, oid: Option<u32> },
+    DocState { oid: u32 },
+    StateVector { oid: u32 },
+    Update { oid: u32, clock: u32 },
+    Meta { oid: u32, name: String },
+    Unknown,
+}
+
+impl KeyKind {
+    fn parse(key: &[u8], value: &[u8]) -> Self {
+        let name = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        match key {
+            [V1, KEYSPACE_OID, doc_name @ .., TERMINATOR] => KeyKind::Oid {
+                doc_name: name(doc_name),
+                oid: value.try_into().ok().map(u32::from_be_bytes),
+            },
+            [V1, KEYSPACE_DOC, o0, o1, o2, o3, rest @ ..] => {
+                let oid = u32::from_be_bytes([*o0, *o1, *o2, *o3]);
+                match rest {
+                    [SUB_DOC] => KeyKind::DocState { oid },
+                    [SUB_STATE_VEC] => KeyKind::StateVector { oid },
+                    [SUB_UPDATE, c0, c1, c2, c3, TERMINATOR] => KeyKind::Update {
+                        oid,
+                        clock: u32::from_be_bytes([*c0, *c1, *c2, *c3]),
+                    },
+                    [SUB_META, meta_name @ .., TERMINATOR] => KeyKind::Meta {
+                        oid,
+                        name: name(meta_name),
+                    },
+                    _ => KeyKind::Unknown,
+                }
+            }
+            _ => KeyKind::Unknown,
+        }
+    }
+}
+
+impl Display for KeyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyKind::Oid {
+                doc_name,
+                oid: Some(oid),
+            } => write!(f, "oid of {:?}: {}", doc_name, oid),
+            KeyKind::Oid {
+                doc_name,
+                oid: None,
+            } => write!(f, "oid of {:?}: invalid", doc_name),
+            KeyKind::DocState { oid } => write!(f, "doc {} state", oid),
+            KeyKind::StateVector { oid } => write!(f, "doc {} state vector", oid),
+            KeyKind::Update { oid, clock } => write!(f, "doc {} pending update {}", oid, clock),
+            KeyKind::Meta { oid, name } => write!(f, "doc {} metadata {:?}", oid, name),
+            KeyKind::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyEntry {
+    pub key: Vec<u8>,
+    pub kind: KeyKind,
+    /// Size of the value in bytes.
+    pub size: usize,
+}
+
+/// The kind of a root type. A stored document does not record the kinds of its root
+/// types, so the kind of a root that was written as a text, array or map is inferred
+/// from its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootKind {
+    Text,
+    Array,
+    Map,
+    XmlFragment,
+    XmlElement,
+    XmlText,
+    /// A root without contents, whose kind cannot be inferred.
+    Empty,
+}
+
+impl Display for RootKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RootKind::Text => "text",
+            RootKind::Array => "array",
+            RootKind::Map => "map",
+            RootKind::XmlFragment => "xml fragment",
+            RootKind::XmlElement => "xml element",
+            RootKind::XmlText => "xml text",
+            RootKind::Empty => "empty",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+pub struct RootType {
+    pub name: String,
+    pub kind: RootKind,
+    /// Length of the root's sequence, which is the text length for texts.
+    pub len: u32,
+    /// Number of keys in the root's map, which holds the entries of maps and the
+    /// attributes of XML elements.
+    pub map_len: u32,
+}
+
+pub struct DocInspection {
+    /// Size of the snapshot in bytes, as stored.
+    pub snapshot_size: usize,
+    pub info: SnapshotInfo,
+    pub keys: Vec<KeyEntry>,
+    /// The state vector of the loaded document.
+    pub state_vector: StateVector,
+    pub roots: Vec<RootType>,
+    doc: yrs::Doc,
+}
+
+impl DocInspection {
+    /// The contents of the document as JSON, as an object with an entry per root type.
+    pub fn to_json(&self) -> serde_json::Value {
+        let txn = self.doc.transact();
+        let contents: serde_json::Map<String, serde_json::Value> = txn
+            .root_refs()
+            .map(|(name, root)| {
+                let (_, root, _) = typed_root(root, &txn);
+                let value =
+                    serde_json::to_value(root.to_json(&txn)).unwrap_or(serde_json::Value::Null);
+                (name.to_owned(), value)
+            })
+            .collect();
+        serde_json::Value::Object(contents)
+    }
+
+    /// Whether the state vector recorded in the snapshot's container matches the state
+    /// vector of the loaded document, or `None` if the container did not record one.
+    pub fn state_vector_matches(&self) -> Option<bool> {
+        let recorded = self.info.state_vector.as_ref()?;
+        Some(
+            StateVector::decode_v1(recorded)
+                .map(|recorded| recorded == self.state_vector)
+                .unwrap_or(false),
+        )
+    }
+}
+
+impl Display for DocInspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Snapshot")?;
+        writeln!(f, "  size: {} bytes", self.snapshot_size)?;
+        writeln!(f, "  format version: {}", self.info.format_version)?;
+        writeln!(f, "  compression: {}", self.info.compression)?;
+        if let Some(created_at) = self.info.created_at_millis {
+            writeln!(f, "  created at: {} ms since epoch", created_at)?;
+        }
+        match self.state_vector_matches() {
+            Some(true) => writeln!(f, "  recorded state vector: matches the document")?,
+            Some(false) => writeln!(f, "  recorded state vector: DOES NOT match the document")?,
+            None => {}
+        }
+
+        writeln!(f, "\nKeys ({})", self.keys.len())?;
+        for entry in &self.keys {
+            writeln!(
+                f,
+                "  {:<40} {:>10} bytes  {}",
+                hex(&entry.key),
+                entry.size,
+                entry.kind
+            )?;
+        }
+
+        let mut clients: Vec<_> = self.state_vector.iter().collect();
+        clients.sort();
+        writeln!(f, "\nState vector ({} clients)", clients.len())?;
+        for (client, clock) in clients {
+            writeln!(f, "  {:>20}: {}", client, clock)?;
+        }
+
+        writeln!(f, "\nRoot types ({})", self.roots.len())?;
+        for root in &self.roots {
+            writeln!(
+                f,
+                "  {:?}: {}, length {}, {} map entries",
+                root.name, root.kind, root.len, root.map_len
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Inspects a snapshot, as stored in a `data.ysweet` file.
+pub fn inspect_snapshot(snapshot: &[u8]) -> Result<DocInspection> {
+    let (info, data) = snapshot::decode_with_info(snapshot)?;
+    let keys = data
+        .iter()
+        .map(|(key, value)| KeyEntry {
+            key: key.clone(),
+            kind: KeyKind::parse(key, value),
+            size: value.len(),
+        })
+        .collect();
+
+    let sync_kv = SyncKv::detached(data);
+    let doc = yrs::Doc::new();
+    sync_kv
+        .load_doc(DOC_NAME, &mut doc.transact_mut())
+        .map_err(|e| anyhow!("Failed to load doc: {:?}", e))?;
+
+    let (state_vector, roots) = {
+        let txn = doc.transact();
+        let roots = txn
+            .root_refs()
+            .map(|(name, root)| {
+                let (kind, _, branch) = typed_root(root, &txn);
+                RootType {
+                    name: name.to_owned(),
+                    kind,
+                    len: branch.len(),
+                    map_len: MapRef::from(branch).len(&txn),
+                }
+            })
+            .collect();
+        (txn.state_vector(), roots)
+    };
+
+    Ok(DocInspection {
+        snapshot_size: snapshot.len(),
+        info,
+        keys,
+        state_vector,
+        roots,
+        doc,
+    })
+}
+
+/// Inspects the stored snapshot of a document.
+pub async fn inspect_doc(store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<DocInspection> {
+    let key = format!("{}{}", doc_id, DOC_SNAPSHOT_SUFFIX);
+    let snapshot = store
+        .get(&key)
+        .await?
+        .ok_or_else(|| anyhow!("Doc {} does not exist in the store.", doc_id))?;
+    inspect_snapshot(&snapshot)
+}
+
+/// Casts a root type to the shared type it was written as. Roots that were loaded
+/// from a stored document are of an undefined type until they are used as a specific
+/// type, so their kind is inferred: a root with map entries is a map, a root whose
+/// sequence holds strings is a text, and any other root with a sequence is an array.
+fn typed_root<T: ReadTxn>(root: Out, txn: &T) -> (RootKind, Out, BranchPtr) {
+    let (kind, branch) = match &root {
+        Out::YText(r) => (RootKind::Text, branch_of(r)),
+        Out::YArray(r) => (RootKind::Array, branch_of(r)),
+        Out::YMap(r) => (RootKind::Map, branch_of(r)),
+        Out::YXmlFragment(r) => (RootKind::XmlFragment, branch_of(r)),
+        Out::YXmlElement(r) => (RootKind::XmlElement, branch_of(r)),
+        Out::YXmlText(r) => (RootKind::XmlText, branch_of(r)),
+        Out::UndefinedRef(branch) => (RootKind::Empty, *branch),
+        _ => unreachable!("root types are always shared types"),
+    };
+    if kind != RootKind::Empty {
+        return (kind, root, branch);
+    }
+
+    if MapRef::from(branch).len(txn) > 0 {
+        (RootKind::Map, Out::YMap(MapRef::from(branch)), branch)
+    } else if !TextRef::from(branch).get_string(txn).is_empty() {
+        (RootKind::Text, Out::YText(TextRef::from(branch)), branch)
+    } else if branch.len() > 0 {
+        (RootKind::Array, Out::YArray(ArrayRef::from(branch)), branch)
+    } else {
+        (RootKind::Empty, root, branch)
+    }
+}
+
+fn branch_of<R: AsRef<Branch>>(root: &R) -> BranchPtr {
+    BranchPtr::from(root.as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{convert::convert, stores::filesystem::FileSystemStore};
+    use serde_json::json;
+    use yrs::{Array, Text, WriteTxn};
+
+    #[tokio::test]
+    async fn test_inspect_doc() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-inspect-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(dir.clone()).unwrap()));
+
+        let doc = yrs::Doc::new();
+        let update = {
+            let mut txn = doc.transact_mut();
+            txn.get_or_insert_text("text").insert(&mut txn, 0, "hello");
+            txn.get_or_insert_array("array").insert(&mut txn, 0, "item");
+            txn.get_or_insert_map("map").insert(&mut txn, "key", 1);
+            txn.encode_update_v1()
+        };
+        convert(store.clone(), &update, "doc1").await.unwrap();
+
+        let inspection = inspect_doc(&store, "doc1").await.unwrap();
+        // Snapshots are written in the legacy format, which records no state vector.
+        assert_eq!(inspection.info.format_version, 0);
+        assert_eq!(inspection.state_vector_matches(), None);
+        assert_eq!(inspection.state_vector, doc.transact().state_vector());
+        assert!(inspection
+            .keys
+            .iter()
+            .any(|entry| matches!(entry.kind, KeyKind::DocState { .. })));
+
+        let mut roots: Vec<_> = inspection
+            .roots
+            .iter()
+            .map(|root| (root.name.as_str(), root.kind, root.len, root.map_len))
+            .collect();
+        roots.sort_by_key(|root| root.0);
+        assert_eq!(
+            roots,
+            vec![
+                ("array", RootKind::Array, 1, 0),
+                ("map", RootKind::Map, 0, 1),
+                ("text", RootKind::Text, 5, 0),
+            ]
+        );
+        assert_eq!(
+            inspection.to_json(),
+            json!({"text": "hello", "array": ["item"], "map": {"key": 1}})
+        );
+
+        assert!(inspect_doc(&store, "missing").await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        use yrs_kvstore::keys::{key_meta, key_oid, key_update};
+
+        assert_eq!(
+            KeyKind::parse(&key_oid(b"doc"), &7u32.to_be_bytes()),
+            KeyKind::Oid {
+                doc_name: "doc".to_owned(),
+                oid: Some(7)
+            }
+        );
+        assert_eq!(
+            KeyKind::parse(&key_update(7, 3), &[]),
+            KeyKind::Update { oid: 7, clock: 3 }
+        );
+        assert_eq!(
+            KeyKind::parse(&key_meta(7, b"name"), &[]),
+            KeyKind::Meta {
+                oid: 7,
+                name: "name".to_owned()
+            }
+        );
+        assert_eq!(KeyKind::parse(&[9, 9], &[]), KeyKind::Unknown);
+    }
+}
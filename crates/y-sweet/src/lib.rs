@@ -1,6 +1,14 @@
 #![doc = include_str!("../README.md")]
 
+pub mod audit;
+pub mod authorizer;
+pub mod backup;
 pub mod cli;
+pub mod compact;
 pub mod convert;
+pub mod inspect;
+pub mod jwt;
+pub mod migrate;
+pub mod otel;
 pub mod server;
 pub mod stores;
@@ -5,22 +5,41 @@ use serde_json::json;
 use std::{
     env,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use tracing::metadata::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 use url::Url;
+use y_sweet::audit::JsonLinesAuditSink;
+use y_sweet::authorizer::{HttpAuthorizer, DEFAULT_AUTHORIZER_TIMEOUT};
+use y_sweet::backup::{export, import};
 use y_sweet::cli::{print_auth_message, print_server_url};
+use y_sweet::compact::compact_doc;
+use y_sweet::inspect::{inspect_doc, inspect_snapshot};
+use y_sweet::jwt::{JwksSource, JwtVerifier, JWKS_REFRESH_INTERVAL};
+use y_sweet::migrate::{migrate, DocMigration};
+use y_sweet::otel::OtlpTracing;
+use y_sweet::server::Server;
+use y_sweet::stores::cache::CachedStore;
 use y_sweet::stores::filesystem::FileSystemStore;
+use y_sweet::stores::postgres::PostgresStore;
+use y_sweet::stores::sqlite::SqliteStore;
 use y_sweet_core::{
-    auth::Authenticator,
+    auth::{Authenticator, KeyId},
+    snapshot::{Compression, SnapshotFormat},
     store::{
-        s3::{S3Config, S3Store},
+        encrypted::{EncryptedStore, EncryptionKeys},
+        list_doc_ids,
+        s3::{S3Config, S3RetryConfig, S3Store},
         Store,
     },
+    tenant::Tenants,
 };
 
 const DEFAULT_S3_REGION: &str = "us-east-1";
@@ -33,6 +52,7 @@ struct Opts {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum ServSubcommand {
     Serve {
         #[clap(env = "Y_SWEET_STORE")]
@@ -45,9 +65,53 @@ enum ServSubcommand {
         #[clap(long, default_value = "10", env = "Y_SWEET_CHECKPOINT_FREQ_SECONDS")]
         checkpoint_freq_seconds: u64,
 
+        /// Cache up to this many megabytes of recently used documents in front of the
+        /// store, so that reopening them does not read them from the store again.
+        #[clap(long, env = "Y_SWEET_STORE_CACHE_MB")]
+        store_cache_mb: Option<u64>,
+
+        /// Keep the store cache in this directory instead of in memory.
+        #[clap(long, env = "Y_SWEET_STORE_CACHE_DIR", requires = "store_cache_mb")]
+        store_cache_dir: Option<PathBuf>,
+
         #[clap(long, env = "Y_SWEET_AUTH")]
         auth: Option<String>,
 
+        /// A JSON file that lists the tenants to host, each with its own key, store
+        /// prefix and quotas. Requires `--auth`.
+        #[clap(long, env = "Y_SWEET_TENANTS", requires = "auth")]
+        tenants: Option<PathBuf>,
+
+        /// Ask this URL what the tokens that clients connect with give access to,
+        /// instead of verifying them as y-sweet doc tokens. Requires `--auth`.
+        #[clap(
+            long,
+            env = "Y_SWEET_AUTHORIZER_URL",
+            requires = "auth",
+            conflicts_with = "tenants"
+        )]
+        authorizer_url: Option<Url>,
+
+        /// How long to cache the access that the authorizer grants.
+        #[clap(long, default_value = "60", env = "Y_SWEET_AUTHORIZER_CACHE_SECONDS")]
+        authorizer_cache_seconds: u64,
+
+        /// Also accept JWTs signed by a key in this JWKS, given as a URL or a file path.
+        #[clap(
+            long,
+            env = "Y_SWEET_JWT_JWKS",
+            requires_all = ["jwt_issuer", "jwt_audience"]
+        )]
+        jwt_jwks: Option<String>,
+
+        /// The issuer (`iss` claim) that JWTs must have.
+        #[clap(long, env = "Y_SWEET_JWT_ISSUER", requires = "jwt_jwks")]
+        jwt_issuer: Option<String>,
+
+        /// The audience (`aud` claim) that JWTs must have.
+        #[clap(long, env = "Y_SWEET_JWT_AUDIENCE", requires = "jwt_jwks")]
+        jwt_audience: Option<String>,
+
         #[clap(long, env = "Y_SWEET_URL_PREFIX")]
         url_prefix: Option<Url>,
 
@@ -59,11 +123,40 @@ enum ServSubcommand {
 
         #[clap(long, default_value = "false", env = "Y_SWEET_SKIP_GC")]
         skip_gc: bool,
+
+        /// Append an audit record of document access to this file, as JSON lines.
+        #[clap(long, env = "Y_SWEET_AUDIT_LOG")]
+        audit_log: Option<PathBuf>,
+
+        /// Record the user ID that a doc token is requested for in the token, so that it
+        /// is audited. Tokens with a user ID cannot be verified by servers older than
+        /// this option, so enable it only once every server has been upgraded.
+        #[clap(long, env = "Y_SWEET_DOC_TOKEN_USER_IDS")]
+        doc_token_user_ids: bool,
+
+        /// On shutdown, how long to wait for dirty documents to be persisted before exiting.
+        #[clap(long, default_value = "25", env = "Y_SWEET_DRAIN_TIMEOUT_SECONDS")]
+        drain_timeout_seconds: u64,
+
+        /// Compress doc snapshots when writing them ("none" or "zstd"). Compressed
+        /// snapshots use the snapshot container. Snapshots are read whichever way they
+        /// were written.
+        #[clap(long, default_value = "none", env = "Y_SWEET_SNAPSHOT_COMPRESSION")]
+        snapshot_compression: Compression,
+
+        /// Write doc snapshots in the container format, which records a checksum and
+        /// the state vector but cannot be read by older versions of Y-Sweet.
+        #[clap(long, default_value = "false", env = "Y_SWEET_SNAPSHOT_CONTAINER")]
+        snapshot_container: bool,
     },
 
     GenAuth {
         #[clap(long)]
         json: bool,
+
+        /// Prefix tokens with this key ID, as tenants' keys are.
+        #[clap(long)]
+        key_id: Option<String>,
     },
 
     /// Convert from a YDoc v1 update format to a .ysweet file.
@@ -77,6 +170,88 @@ enum ServSubcommand {
         doc_id: String,
     },
 
+    /// Copy every document from one store to another, verifying each copy.
+    Migrate {
+        /// The store to copy documents from.
+        #[clap(long)]
+        from: String,
+
+        /// The store to copy documents to.
+        #[clap(long)]
+        to: String,
+
+        /// Skip documents that were already copied by a previous run, and overwrite
+        /// documents in the destination that differ from the source. Without this,
+        /// documents that exist in the destination are left alone and reported as failed.
+        #[clap(long)]
+        resume: bool,
+    },
+
+    /// Write every document in a store to a tar archive, as Yjs v1 updates.
+    Export {
+        /// The store to export documents from.
+        #[clap(env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The archive to write.
+        #[clap(long)]
+        out: PathBuf,
+    },
+
+    /// Restore the documents in an archive written by `export` into a store.
+    Import {
+        /// The store to restore documents into.
+        #[clap(env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The archive to read.
+        #[clap(long = "in")]
+        input: PathBuf,
+    },
+
+    /// Rebuild documents from their current state with garbage collection, dropping
+    /// the contents of deleted items, and write them back to the store. Documents that
+    /// a server has loaded must be compacted through its compact endpoint instead.
+    Compact {
+        /// The store that holds the documents.
+        #[clap(env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The IDs of the documents to compact.
+        #[clap(required_unless_present = "all")]
+        doc_ids: Vec<String>,
+
+        /// Compact every document in the store.
+        #[clap(long, conflicts_with = "doc_ids")]
+        all: bool,
+
+        /// Report the size each document would have after compaction without writing it.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Compress the compacted snapshots ("none" or "zstd").
+        #[clap(long, default_value = "none", env = "Y_SWEET_SNAPSHOT_COMPRESSION")]
+        snapshot_compression: Compression,
+
+        /// Write the compacted snapshots in the container format.
+        #[clap(long, default_value = "false", env = "Y_SWEET_SNAPSHOT_CONTAINER")]
+        snapshot_container: bool,
+    },
+
+    /// Show how a stored document is laid out: its snapshot, the keys of its key-value
+    /// map, its state vector and its root types.
+    Inspect {
+        /// A `data.ysweet` file, or the store to read the document from.
+        target: String,
+
+        /// The ID of the document to inspect, if `target` is a store.
+        doc_id: Option<String>,
+
+        /// Also print the contents of the document as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
     Version,
 
     ServeDoc {
@@ -94,6 +269,25 @@ enum ServSubcommand {
 
         #[clap(long, default_value = "false", env = "Y_SWEET_SKIP_GC")]
         skip_gc: bool,
+
+        /// Append an audit record of document access to this file, as JSON lines.
+        #[clap(long, env = "Y_SWEET_AUDIT_LOG")]
+        audit_log: Option<PathBuf>,
+
+        /// On shutdown, how long to wait for dirty documents to be persisted before exiting.
+        #[clap(long, default_value = "25", env = "Y_SWEET_DRAIN_TIMEOUT_SECONDS")]
+        drain_timeout_seconds: u64,
+
+        /// Compress doc snapshots when writing them ("none" or "zstd"). Compressed
+        /// snapshots use the snapshot container. Snapshots are read whichever way they
+        /// were written.
+        #[clap(long, default_value = "none", env = "Y_SWEET_SNAPSHOT_COMPRESSION")]
+        snapshot_compression: Compression,
+
+        /// Write doc snapshots in the container format, which records a checksum and
+        /// the state vector but cannot be read by older versions of Y-Sweet.
+        #[clap(long, default_value = "false", env = "Y_SWEET_SNAPSHOT_CONTAINER")]
+        snapshot_container: bool,
     },
 }
 
@@ -103,6 +297,10 @@ const S3_SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
 const S3_REGION: &str = "AWS_REGION";
 const S3_ENDPOINT: &str = "AWS_ENDPOINT_URL_S3";
 const S3_USE_PATH_STYLE: &str = "AWS_S3_USE_PATH_STYLE";
+const S3_MAX_ATTEMPTS: &str = "AWS_MAX_ATTEMPTS";
+const S3_REQUEST_TIMEOUT_SECONDS: &str = "Y_SWEET_S3_REQUEST_TIMEOUT_SECONDS";
+const ENCRYPTION_KEYS: &str = "Y_SWEET_ENCRYPTION_KEYS";
+const ENCRYPTION_ALLOW_PLAINTEXT: &str = "Y_SWEET_ENCRYPTION_ALLOW_PLAINTEXT";
 fn parse_s3_config_from_env_and_args(
     bucket: String,
     prefix: Option<String>,
@@ -122,6 +320,22 @@ fn parse_s3_config_from_env_and_args(
         false
     };
 
+    let mut retry = S3RetryConfig::default();
+    if let Ok(max_attempts) = env::var(S3_MAX_ATTEMPTS) {
+        retry.max_attempts = max_attempts
+            .parse()
+            .with_context(|| format!("{} must be a positive integer", S3_MAX_ATTEMPTS))?;
+        if retry.max_attempts == 0 {
+            anyhow::bail!("{} must be a positive integer", S3_MAX_ATTEMPTS);
+        }
+    }
+    if let Ok(timeout) = env::var(S3_REQUEST_TIMEOUT_SECONDS) {
+        let timeout: u64 = timeout.parse().with_context(|| {
+            format!("{} must be a number of seconds", S3_REQUEST_TIMEOUT_SECONDS)
+        })?;
+        retry.request_timeout = Duration::from_secs(timeout);
+    }
+
     Ok(S3Config {
         key: env::var(S3_ACCESS_KEY_ID)
             .map_err(|_| anyhow::anyhow!("{} env var not supplied", S3_ACCESS_KEY_ID))?,
@@ -139,11 +353,18 @@ fn parse_s3_config_from_env_and_args(
         bucket_prefix: prefix,
         // If the endpoint is overridden, we assume that the user wants path-style URLs.
         path_style,
+        retry,
     })
 }
 
 fn get_store_from_opts(store_path: &str) -> Result<Box<dyn Store>> {
-    if store_path.starts_with("s3://") {
+    with_encryption_from_env(open_store(store_path)?)
+}
+
+/// Opens the store at `store_path`, without the encryption that `get_store_from_opts`
+/// adds.
+fn open_store(store_path: &str) -> Result<Box<dyn Store>> {
+    let store: Box<dyn Store> = if store_path.starts_with("s3://") {
         let url = url::Url::parse(store_path)?;
         let bucket = url
             .host_str()
@@ -152,11 +373,131 @@ fn get_store_from_opts(store_path: &str) -> Result<Box<dyn Store>> {
         let bucket_prefix = url.path().trim_start_matches('/').to_owned();
         let bucket_prefix = (!bucket_prefix.is_empty()).then_some(bucket_prefix); // "" => None
         let config = parse_s3_config_from_env_and_args(bucket, bucket_prefix)?;
-        let store = S3Store::new(config);
-        Ok(Box::new(store))
+        Box::new(S3Store::new(config))
+    } else if store_path.starts_with("postgres://") || store_path.starts_with("postgresql://") {
+        Box::new(PostgresStore::new(store_path)?)
+    } else if let Some(path) = store_path.strip_prefix("sqlite://") {
+        let store = SqliteStore::new(Path::new(path))
+            .with_context(|| format!("Failed to open SQLite database {}", path))?;
+        Box::new(store)
+    } else {
+        Box::new(FileSystemStore::new(PathBuf::from(store_path))?)
+    };
+    Ok(store)
+}
+
+/// Wraps the store in an `EncryptedStore` if encryption keys are configured.
+fn with_encryption_from_env(store: Box<dyn Store>) -> Result<Box<dyn Store>> {
+    let Ok(keys) = env::var(ENCRYPTION_KEYS) else {
+        return Ok(store);
+    };
+    let keys = EncryptionKeys::parse(&keys)
+        .with_context(|| format!("Failed to parse {}", ENCRYPTION_KEYS))?;
+    let plaintext_reads = match env::var(ENCRYPTION_ALLOW_PLAINTEXT) {
+        Ok(value) if value.eq_ignore_ascii_case("true") => true,
+        Ok(value) if value.eq_ignore_ascii_case("false") || value.is_empty() => false,
+        Ok(_) => anyhow::bail!(
+            "If {} is set, it must be either \"true\" or \"false\"",
+            ENCRYPTION_ALLOW_PLAINTEXT
+        ),
+        Err(_) => false,
+    };
+    Ok(Box::new(
+        EncryptedStore::new(store, keys).with_plaintext_reads(plaintext_reads),
+    ))
+}
+
+/// Wraps the store in a `CachedStore` if a cache size is configured. This goes below
+/// `with_encryption_from_env`, so that only encrypted objects are cached.
+fn with_store_cache(
+    store: Box<dyn Store>,
+    cache_mb: Option<u64>,
+    cache_dir: Option<PathBuf>,
+) -> Result<Box<dyn Store>> {
+    let Some(cache_mb) = cache_mb else {
+        return Ok(store);
+    };
+    let max_bytes = cache_mb * 1024 * 1024;
+    let store = if let Some(cache_dir) = cache_dir {
+        CachedStore::on_disk(store, cache_dir.clone(), max_bytes)
+            .with_context(|| format!("Failed to create store cache in {}", cache_dir.display()))?
+    } else {
+        CachedStore::in_memory(store, max_bytes)
+    };
+    Ok(Box::new(store))
+}
+
+fn with_audit_log(server: Server, audit_log: Option<&Path>) -> Result<Server> {
+    if let Some(audit_log) = audit_log {
+        let sink = JsonLinesAuditSink::new(audit_log)
+            .with_context(|| format!("Failed to open audit log {}", audit_log.display()))?;
+        Ok(server.with_audit_sink(Arc::new(sink)))
     } else {
-        Ok(Box::new(FileSystemStore::new(PathBuf::from(store_path))?))
+        Ok(server)
+    }
+}
+
+fn load_tenants(path: &Path, auth: Option<&Authenticator>) -> Result<Tenants> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read tenants from {}", path.display()))?;
+    let tenants = Tenants::from_json(&json)
+        .with_context(|| format!("Failed to load tenants from {}", path.display()))?;
+    if let Some(key_id) = auth.and_then(|auth| auth.key_id()) {
+        if tenants.get(key_id).is_some() {
+            anyhow::bail!("Tenant {} has the same key ID as --auth.", key_id);
+        }
+    }
+    Ok(tenants)
+}
+
+/// Resolves when the process receives Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("Received Ctrl+C, shutting down.");
+        },
+        _ = async {
+            #[cfg(unix)]
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => signal.recv().await,
+                Err(e) => {
+                    tracing::error!("Failed to install SIGTERM handler: {}", e);
+                    std::future::pending::<Option<()>>().await
+                }
+            }
+
+            #[cfg(not(unix))]
+            std::future::pending::<Option<()>>().await
+        } => {
+            tracing::info!("Received SIGTERM, shutting down.");
+        }
+    }
+}
+
+/// Drains the server, then stops it. Draining and stopping together are bounded by the
+/// server's drain timeout, so that the process exits within it.
+async fn drain_and_shut_down(
+    server: &Server,
+    token: &CancellationToken,
+    handle: JoinHandle<Result<()>>,
+) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + server.drain_timeout();
+    let report = server.drain_until(deadline).await;
+    if !report.failed.is_empty() {
+        tracing::error!(
+            failed = ?report.failed,
+            "Some documents were not persisted before shutdown."
+        );
     }
+
+    token.cancel();
+    match tokio::time::timeout_at(deadline, handle).await {
+        Ok(result) => result??,
+        Err(_) => tracing::warn!("Server did not stop before drain timeout."),
+    }
+
+    tracing::info!("Server shut down.");
+    Ok(())
 }
 
 #[tokio::main]
@@ -166,8 +507,10 @@ async fn main() -> Result<()> {
     let filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
         .from_env_lossy();
+    let otlp_tracing = OtlpTracing::from_env()?;
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer())
+        .with(otlp_tracing.as_ref().map(|otlp| otlp.layer()))
         .with(filter)
         .init();
 
@@ -177,16 +520,39 @@ async fn main() -> Result<()> {
             host,
             checkpoint_freq_seconds,
             store,
+            store_cache_mb,
+            store_cache_dir,
             auth,
+            tenants,
+            authorizer_url,
+            authorizer_cache_seconds,
+            jwt_jwks,
+            jwt_issuer,
+            jwt_audience,
             url_prefix,
             prod,
             max_body_size,
             skip_gc,
+            audit_log,
+            doc_token_user_ids,
+            drain_timeout_seconds,
+            snapshot_compression,
+            snapshot_container,
         } => {
             let auth = if let Some(auth) = auth {
                 Some(Authenticator::new(auth)?)
             } else {
-                tracing::warn!("No auth key set. Only use this for local development!");
+                if jwt_jwks.is_none() {
+                    tracing::warn!("No auth key set. Only use this for local development!");
+                }
+                None
+            };
+
+            let tenants = if let Some(tenants) = tenants {
+                let tenants = load_tenants(tenants, auth.as_ref())?;
+                tracing::info!("Hosting {} tenants.", tenants.len());
+                Some(tenants)
+            } else {
                 None
             };
 
@@ -199,7 +565,9 @@ async fn main() -> Result<()> {
             let addr = listener.local_addr()?;
 
             let store = if let Some(store) = store {
-                let store = get_store_from_opts(store)?;
+                let store = open_store(store)?;
+                let store = with_store_cache(store, *store_cache_mb, store_cache_dir.clone())?;
+                let store = with_encryption_from_env(store)?;
                 store.init().await?;
                 Some(store)
             } else {
@@ -213,9 +581,9 @@ async fn main() -> Result<()> {
 
             let token = CancellationToken::new();
 
-            let server = y_sweet::server::Server::new(
+            let server = Server::new(
                 store,
-                std::time::Duration::from_secs(*checkpoint_freq_seconds),
+                Duration::from_secs(*checkpoint_freq_seconds),
                 auth,
                 url_prefix.clone(),
                 token.clone(),
@@ -224,30 +592,68 @@ async fn main() -> Result<()> {
                 *skip_gc,
             )
             .await?;
+            let server = with_audit_log(server, audit_log.as_deref())?
+                .with_doc_token_user_ids(*doc_token_user_ids)
+                .with_drain_timeout(Duration::from_secs(*drain_timeout_seconds))
+                .with_snapshot_format(SnapshotFormat::new(
+                    *snapshot_container,
+                    *snapshot_compression,
+                ));
+            let server = match tenants {
+                Some(tenants) => server.with_tenants(tenants),
+                None => server,
+            };
+            let server = if let Some(authorizer_url) = authorizer_url {
+                let authorizer = HttpAuthorizer::new(
+                    authorizer_url.clone(),
+                    Duration::from_secs(*authorizer_cache_seconds),
+                    DEFAULT_AUTHORIZER_TIMEOUT,
+                )?;
+                server.with_authorizer(Arc::new(authorizer))
+            } else {
+                server
+            };
+            let server = if let Some(jwt_jwks) = jwt_jwks {
+                let jwt = JwtVerifier::new(
+                    JwksSource::parse(jwt_jwks)?,
+                    jwt_issuer.clone().unwrap_or_default(),
+                    jwt_audience.clone().unwrap_or_default(),
+                )
+                .await
+                .with_context(|| format!("Failed to load JWKS from {}", jwt_jwks))?;
+                let jwt = Arc::new(jwt);
+                tokio::spawn({
+                    let jwt = jwt.clone();
+                    let token = token.clone();
+                    async move { jwt.refresh_periodically(JWKS_REFRESH_INTERVAL, token).await }
+                });
+                server.with_jwt_verifier(jwt)
+            } else {
+                server
+            };
+            let server = Arc::new(server);
 
             let prod = *prod;
-            let handle = tokio::spawn(async move {
-                server.serve(listener, prod).await.unwrap();
-            });
+            let handle = tokio::spawn(server.clone().serve(listener, prod));
 
             tracing::info!("Listening on ws://{}", addr);
 
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Failed to install CTRL+C signal handler");
-
-            tracing::info!("Shutting down.");
-            token.cancel();
-
-            handle.await?;
-            tracing::info!("Server shut down.");
+            tokio::select! {
+                _ = shutdown_signal() => {}
+                _ = token.cancelled() => tracing::info!("Drained, shutting down."),
+            }
+            drain_and_shut_down(&server, &token, handle).await?;
         }
-        ServSubcommand::GenAuth { json } => {
-            let auth = Authenticator::gen_key()?;
+        ServSubcommand::GenAuth { json, key_id } => {
+            let mut auth = Authenticator::gen_key()?;
+            if let Some(key_id) = key_id {
+                auth = auth.with_key_id(KeyId::new(key_id.clone())?);
+            }
 
             if *json {
                 let result = json!({
                     "private_key": auth.private_key(),
+                    "key_id": auth.key_id(),
                     "server_token": auth.server_token(),
                 });
 
@@ -257,7 +663,7 @@ async fn main() -> Result<()> {
             }
         }
         ServSubcommand::ConvertFromUpdate { store, doc_id } => {
-            let store = get_store_from_opts(store)?;
+            let store = Arc::new(get_store_from_opts(store)?);
             store.init().await?;
 
             let mut stdin = tokio::io::stdin();
@@ -266,6 +672,134 @@ async fn main() -> Result<()> {
 
             y_sweet::convert::convert(store, &buf, doc_id).await?;
         }
+        ServSubcommand::Migrate { from, to, resume } => {
+            let from = Arc::new(get_store_from_opts(from)?);
+            from.init().await?;
+            let to = Arc::new(get_store_from_opts(to)?);
+            to.init().await?;
+
+            let report = migrate(
+                &from,
+                &to,
+                *resume,
+                |index, total, doc_id, result| match result {
+                    Ok(DocMigration::Copied) => println!("[{index}/{total}] {doc_id}: copied"),
+                    Ok(DocMigration::AlreadyCopied) => {
+                        println!("[{index}/{total}] {doc_id}: already copied")
+                    }
+                    Err(e) => println!("[{index}/{total}] {doc_id}: failed: {e:#}"),
+                },
+            )
+            .await?;
+
+            println!(
+                "Copied {} documents, skipped {}, failed {}.",
+                report.copied,
+                report.skipped,
+                report.failed.len()
+            );
+            if !report.failed.is_empty() {
+                anyhow::bail!("{} documents could not be copied", report.failed.len());
+            }
+        }
+        ServSubcommand::Export { store, out } => {
+            let store = Arc::new(get_store_from_opts(store)?);
+            store.init().await?;
+
+            let manifest = export(&store, out, |index, total, doc_id| {
+                println!("[{index}/{total}] {doc_id}: exported")
+            })
+            .await?;
+            println!(
+                "Exported {} documents to {}.",
+                manifest.docs.len(),
+                out.display()
+            );
+        }
+        ServSubcommand::Import { store, input } => {
+            let store = Arc::new(get_store_from_opts(store)?);
+            store.init().await?;
+
+            let manifest = import(&store, input, |doc_id| println!("{doc_id}: imported")).await?;
+            println!(
+                "Imported {} documents from {}.",
+                manifest.docs.len(),
+                input.display()
+            );
+        }
+        ServSubcommand::Compact {
+            store,
+            doc_ids,
+            all,
+            dry_run,
+            snapshot_compression,
+            snapshot_container,
+        } => {
+            let store = Arc::new(get_store_from_opts(store)?);
+            store.init().await?;
+
+            let doc_ids = if *all {
+                list_doc_ids(store.as_ref().as_ref()).await?
+            } else {
+                doc_ids.clone()
+            };
+
+            let (mut before, mut after, mut failed) = (0, 0, 0);
+            for doc_id in &doc_ids {
+                let format = SnapshotFormat::new(*snapshot_container, *snapshot_compression);
+                match compact_doc(&store, doc_id, *dry_run, format).await {
+                    Ok(report) => {
+                        println!(
+                            "{doc_id}: {} -> {} bytes",
+                            report.size_before, report.size_after
+                        );
+                        before += report.size_before;
+                        after += report.size_after;
+                    }
+                    Err(e) => {
+                        println!("{doc_id}: failed: {e:#}");
+                        failed += 1;
+                    }
+                }
+            }
+
+            let verb = if *dry_run {
+                "Would compact"
+            } else {
+                "Compacted"
+            };
+            println!(
+                "{verb} {} documents from {before} to {after} bytes, failed {failed}.",
+                doc_ids.len() - failed
+            );
+            if failed > 0 {
+                anyhow::bail!("{} documents could not be compacted", failed);
+            }
+        }
+        ServSubcommand::Inspect {
+            target,
+            doc_id,
+            json,
+        } => {
+            let inspection = match doc_id {
+                Some(doc_id) => {
+                    let store = Arc::new(get_store_from_opts(target)?);
+                    store.init().await?;
+                    inspect_doc(&store, doc_id).await?
+                }
+                None => {
+                    let snapshot = std::fs::read(target)
+                        .with_context(|| format!("Failed to read {}", target))?;
+                    inspect_snapshot(&snapshot)?
+                }
+            };
+
+            print!("{}", inspection);
+            if *json {
+                println!("\nContents");
+                println!("{}", serde_json::to_string_pretty(&inspection.to_json())?);
+            }
+        }
         ServSubcommand::Version => {
             println!("{}", VERSION);
         }
@@ -275,6 +809,10 @@ async fn main() -> Result<()> {
             checkpoint_freq_seconds,
             max_body_size,
             skip_gc,
+            audit_log,
+            drain_timeout_seconds,
+            snapshot_compression,
+            snapshot_container,
         } => {
             let doc_id = env::var("SESSION_BACKEND_KEY").expect("SESSION_BACKEND_KEY must be set");
 
@@ -301,7 +839,7 @@ async fn main() -> Result<()> {
 
                 let s3_config = parse_s3_config_from_env_and_args(bucket, prefix)?;
                 let store = S3Store::new(s3_config);
-                let store: Box<dyn Store> = Box::new(store);
+                let store = with_encryption_from_env(Box::new(store))?;
                 store.init().await?;
                 Some(store)
             } else {
@@ -313,9 +851,9 @@ async fn main() -> Result<()> {
             };
 
             let cancellation_token = CancellationToken::new();
-            let server = y_sweet::server::Server::new(
+            let server = Server::new(
                 store,
-                std::time::Duration::from_secs(*checkpoint_freq_seconds),
+                Duration::from_secs(*checkpoint_freq_seconds),
                 None, // No authenticator
                 None, // No URL prefix
                 cancellation_token.clone(),
@@ -324,6 +862,13 @@ async fn main() -> Result<()> {
                 *skip_gc,
             )
             .await?;
+            let server = with_audit_log(server, audit_log.as_deref())?
+                .with_drain_timeout(Duration::from_secs(*drain_timeout_seconds))
+                .with_snapshot_format(SnapshotFormat::new(
+                    *snapshot_container,
+                    *snapshot_compression,
+                ));
+            let server = Arc::new(server);
 
             // Load the one document we're operating with
             server
@@ -339,37 +884,18 @@ async fn main() -> Result<()> {
             let listener = TcpListener::bind(addr).await?;
             let addr = listener.local_addr()?;
 
-            tokio::spawn(async move {
-                server.serve_doc(listener, false).await.unwrap();
-            });
+            let handle = tokio::spawn(server.clone().serve_doc(listener, false));
 
             tracing::info!("Listening on http://{}", addr);
 
-            tokio::select! {
-                _ = tokio::signal::ctrl_c() => {
-                    tracing::info!("Received Ctrl+C, shutting down.");
-                },
-                _ = async {
-                    #[cfg(unix)]
-                    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
-                        Ok(mut signal) => signal.recv().await,
-                        Err(e) => {
-                            tracing::error!("Failed to install SIGTERM handler: {}", e);
-                            std::future::pending::<Option<()>>().await
-                        }
-                    }
-
-                    #[cfg(not(unix))]
-                    std::future::pending::<Option<()>>().await
-                } => {
-                    tracing::info!("Received SIGTERM, shutting down.");
-                }
-            }
-
-            cancellation_token.cancel();
-            tracing::info!("Server shut down.");
+            shutdown_signal().await;
+            drain_and_shut_down(&server, &cancellation_token, handle).await?;
         }
     }
 
+    if let Some(otlp_tracing) = otlp_tracing {
+        otlp_tracing.shutdown()?;
+    }
+
     Ok(())
 }
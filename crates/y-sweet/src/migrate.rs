@@ -0,0 +1,194 @@
+use crate::convert::load_doc;
+use anyhow::{anyhow, bail, Context, Result};
+use std::sync::Arc;
+use y_sweet_core::store::{list_doc_ids, Store, DOC_METADATA_SUFFIX, DOC_SNAPSHOT_SUFFIX};
+use yrs::{ReadTxn, StateVector, Transact};
+
+/// What happened to a doc during a migration.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DocMigration {
+    Copied,
+    /// The destination already had the doc in the same state, and the migration was
+    /// resumed.
+    AlreadyCopied,
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub copied: usize,
+    pub skipped: usize,
+    /// IDs of the docs that could not be copied, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Copies every doc in `from` to `to`, calling `on_progress` with the position of the
+/// doc, the number of docs and the outcome after each one.
+///
+/// Unless `resume` is set, docs that already exist in `to` are not overwritten and are
+/// reported as failed. With `resume`, docs whose copy matches the source are skipped,
+/// so that an interrupted migration can be run again, and other existing docs are
+/// overwritten with the source.
+pub async fn migrate<F>(
+    from: &Arc<Box<dyn Store>>,
+    to: &Arc<Box<dyn Store>>,
+    resume: bool,
+    mut on_progress: F,
+) -> Result<MigrationReport>
+where
+    F: FnMut(usize, usize, &str, &Result<DocMigration>),
+{
+    let doc_ids = list_doc_ids(from.as_ref().as_ref())
+        .await
+        .context("Failed to list docs in the source store")?;
+
+    let mut report = MigrationReport::default();
+    for (index, doc_id) in doc_ids.iter().enumerate() {
+        let result = migrate_doc(from, to, doc_id, resume).await;
+        on_progress(index + 1, doc_ids.len(), doc_id, &result);
+        match result {
+            Ok(DocMigration::Copied) => report.copied += 1,
+            Ok(DocMigration::AlreadyCopied) => report.skipped += 1,
+            Err(e) => report.failed.push((doc_id.clone(), format!("{:#}", e))),
+        }
+    }
+    Ok(report)
+}
+
+/// Copies the snapshot and metadata of one doc, and verifies the copy by loading it
+/// from `to` and comparing its state vector with the source's.
+pub async fn migrate_doc(
+    from: &Arc<Box<dyn Store>>,
+    to: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    resume: bool,
+) -> Result<DocMigration> {
+    let key = format!("{}{}", doc_id, DOC_SNAPSHOT_SUFFIX);
+    let source_state = load_state_vector(from, doc_id)
+        .await
+        .context("Failed to load doc from the source store")?;
+
+    if to.exists(&key).await? {
+        if !resume {
+            bail!("Doc already exists in the destination store.");
+        }
+        if let Ok(state) = load_state_vector(to, doc_id).await {
+            if state == source_state {
+                copy_metadata(from, to, doc_id).await?;
+                return Ok(DocMigration::AlreadyCopied);
+            }
+        }
+    }
+
+    let snapshot = from
+        .get(&key)
+        .await?
+        .ok_or_else(|| anyhow!("Doc was removed from the source store."))?;
+    to.set(&key, snapshot).await?;
+
+    let copied_state = load_state_vector(to, doc_id)
+        .await
+        .context("Failed to load copied doc from the destination store")?;
+    if copied_state != source_state {
+        bail!("State vector of the copied doc does not match the source.");
+    }
+    copy_metadata(from, to, doc_id).await?;
+    Ok(DocMigration::Copied)
+}
+
+async fn copy_metadata(
+    from: &Arc<Box<dyn Store>>,
+    to: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<()> {
+    let key = format!("{}{}", doc_id, DOC_METADATA_SUFFIX);
+    if let Some(metadata) = from.get(&key).await? {
+        to.set(&key, metadata).await?;
+    }
+    Ok(())
+}
+
+async fn load_state_vector(store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<StateVector> {
+    let doc = load_doc(store, doc_id).await?;
+    let state_vector = doc.transact().state_vector();
+    Ok(state_vector)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{convert::convert, stores::filesystem::FileSystemStore};
+    use std::path::{Path, PathBuf};
+    use yrs::{Text, WriteTxn};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("y-sweet-migrate-{}", nanoid::nanoid!()))
+    }
+
+    fn store(dir: &Path) -> Box<dyn Store> {
+        Box::new(FileSystemStore::new(dir.to_owned()).unwrap())
+    }
+
+    async fn store_doc(dir: &Path, doc_id: &str, text: &str) {
+        let doc = yrs::Doc::new();
+        let update = {
+            let mut txn = doc.transact_mut();
+            txn.get_or_insert_text("text").insert(&mut txn, 0, text);
+            txn.encode_update_v1()
+        };
+        convert(Arc::new(store(dir)), &update, doc_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let (from_dir, to_dir) = (temp_dir(), temp_dir());
+        let from = Arc::new(store(&from_dir));
+        let to = Arc::new(store(&to_dir));
+
+        store_doc(&from_dir, "doc1", "hello").await;
+        store_doc(&from_dir, "doc2", "world").await;
+        let metadata_key = format!("doc1{}", DOC_METADATA_SUFFIX);
+        from.set(&metadata_key, br#"{"tags":["a"]}"#.to_vec())
+            .await
+            .unwrap();
+
+        let mut progress = Vec::new();
+        let report = migrate(&from, &to, false, |index, total, doc_id, _| {
+            progress.push((index, total, doc_id.to_owned()))
+        })
+        .await
+        .unwrap();
+        assert_eq!(report.copied, 2);
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            progress,
+            vec![(1, 2, "doc1".to_owned()), (2, 2, "doc2".to_owned())]
+        );
+        assert_eq!(
+            load_state_vector(&to, "doc1").await.unwrap(),
+            load_state_vector(&from, "doc1").await.unwrap()
+        );
+        assert_eq!(
+            to.get(&metadata_key).await.unwrap(),
+            from.get(&metadata_key).await.unwrap()
+        );
+
+        // Running again refuses to overwrite the copies.
+        let report = migrate(&from, &to, false, |_, _, _, _| ()).await.unwrap();
+        assert_eq!(report.failed.len(), 2);
+
+        // Resuming skips copies that are up to date, and copies docs that changed.
+        store_doc(&from_dir, "doc2", "!").await;
+        let report = migrate(&from, &to, true, |_, _, _, _| ()).await.unwrap();
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.copied, 1);
+        assert_eq!(
+            load_state_vector(&to, "doc2").await.unwrap(),
+            load_state_vector(&from, "doc2").await.unwrap()
+        );
+
+        std::fs::remove_dir_all(from_dir).unwrap();
+        std::fs::remove_dir_all(to_dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+use axum::{extract::Request, middleware::Next, response::Response};
+use opentelemetry::{global, trace::TracerProvider as _};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use std::env;
+use tracing::{Instrument, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+const OTEL_EXPORTER_OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+const OTEL_EXPORTER_OTLP_TRACES_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+const TRACER_NAME: &str = "y-sweet";
+
+/// Exports spans to an OTLP collector over HTTP.
+///
+/// The exporter is only enabled if `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. The remaining `OTEL_*` environment
+/// variables (e.g. `OTEL_SERVICE_NAME`, `OTEL_EXPORTER_OTLP_HEADERS`) are read by the
+/// OpenTelemetry SDK itself.
+pub struct OtlpTracing {
+    provider: SdkTracerProvider,
+}
+
+impl OtlpTracing {
+    pub fn from_env() -> Result<Option<Self>> {
+        if env::var_os(OTEL_EXPORTER_OTLP_ENDPOINT).is_none()
+            && env::var_os(OTEL_EXPORTER_OTLP_TRACES_ENDPOINT).is_none()
+        {
+            return Ok(None);
+        }
+
+        let exporter = SpanExporter::builder().with_http().build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .build();
+
+        // Incoming requests carry their trace context in W3C `traceparent` headers.
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Some(Self { provider }))
+    }
+
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer(TRACER_NAME))
+    }
+
+    /// Flush any spans that have not been exported yet.
+    pub fn shutdown(self) -> Result<()> {
+        self.provider.shutdown()?;
+        Ok(())
+    }
+}
+
+/// Wraps each HTTP request in a span whose parent is the W3C trace context
+/// sent by the caller, if any. Spans created while handling the request (including
+/// store writes) then belong to the caller's trace.
+pub async fn trace_context_middleware(req: Request, next: Next) -> Response {
+    let parent_context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+
+    let span = tracing::info_span!(
+        "request",
+        otel.name = format!("{} {}", req.method(), req.uri().path()),
+        http.method = %req.method(),
+        http.path = %req.uri().path(),
+    );
+    if let Err(e) = span.set_parent(parent_context) {
+        tracing::debug!(?e, "Could not set parent trace context");
+    }
+
+    next.run(req).instrument(span).await
+}
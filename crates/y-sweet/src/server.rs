@@ -1,12 +1,17 @@
+use crate::{
+    audit::{AuditEventKind, AuditLog, AuditSink, AuditSubject},
+    jwt::JwtVerifier,
+    otel::trace_context_middleware,
+};
 use anyhow::{anyhow, Result};
 use axum::{
     body::Bytes,
     extract::{
-        ws::{Message, WebSocket},
+        ws::{close_code, CloseFrame, Message, WebSocket},
         DefaultBodyLimit, Path, Query, Request, State, WebSocketUpgrade,
     },
     http::{
-        header::{HeaderMap, HeaderName},
+        header::{HeaderMap, HeaderName, CONTENT_TYPE},
         StatusCode,
     },
     middleware::{self, Next},
@@ -15,33 +20,54 @@ use axum::{
     Json, Router,
 };
 use axum_extra::typed_header::TypedHeader;
-use dashmap::{mapref::one::MappedRef, DashMap};
-use futures::{SinkExt, StreamExt};
-use serde::Deserialize;
+use dashmap::{mapref::one::MappedRef, DashMap, DashSet};
+use data_encoding::BASE64;
+use futures::{stream::FuturesUnordered, SinkExt, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
-    sync::{Arc, RwLock},
+    collections::{BTreeSet, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
     time::Duration,
 };
 use tokio::{
     net::TcpListener,
-    sync::mpsc::{channel, Receiver},
+    sync::{
+        mpsc::{channel, Receiver},
+        OwnedMutexGuard,
+    },
+    time::Instant,
 };
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{span, Instrument, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use url::Url;
 use y_sweet_core::{
     api_types::{
-        validate_doc_name, AuthDocRequest, Authorization, ClientToken, DocCreationRequest,
-        NewDocResponse,
+        validate_doc_name, validate_doc_pattern, AuthDocRequest, Authorization, ClientToken,
+        CompactDocRequest, CompactionReport, DocCreationRequest, DocForkRequest, DocListing,
+        DocMetadata, DocStats, ListDocsResponse, NewDocResponse,
+    },
+    auth::{
+        doc_pattern_matches, AuthError, Authenticator, ExpirationTimeEpochMillis,
+        DEFAULT_EXPIRATION_SECONDS,
     },
-    auth::{Authenticator, ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS},
+    authorizer::{Authorizer, AuthorizerError},
     doc_connection::DocConnection,
     doc_sync::DocWithSyncKv,
-    store::Store,
+    snapshot::SnapshotFormat,
+    store::{
+        list_doc_ids_page, list_doc_ids_with_prefix, Store, StoreError, DOC_METADATA_SUFFIX,
+        DOC_SNAPSHOT_SUFFIX,
+    },
     sync::awareness::Awareness,
     sync_kv::SyncKv,
+    tenant::{Tenant, Tenants},
 };
+use yrs::{updates::decoder::Decode, Snapshot, Update};
 
 const PLANE_VERIFIED_USER_DATA_HEADER: &str = "x-verified-user-data";
 
@@ -51,12 +77,78 @@ const PING_EVERY: Duration = Duration::from_secs(20);
 // All modern browsers will respond to websocket pings with a pong message.
 const PONG_TIMEOUT: Duration = Duration::from_secs(40);
 
+// How long `drain` waits for dirty docs to be persisted, unless configured otherwise.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(25);
+
+// How many docs' metadata `list_docs` reads from the store at once.
+const LIST_DOCS_CONCURRENCY: usize = 16;
+
+// How many docs a page of `GET /docs` holds, unless the request asks for fewer or more.
+const DEFAULT_LIST_DOCS_LIMIT: usize = 100;
+const MAX_LIST_DOCS_LIMIT: usize = 1000;
+
+// How long a readiness check waits on the store before reporting it unavailable.
+const STORE_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often the store is checked in the background, for the readiness endpoint.
+const STORE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
 fn current_time_epoch_millis() -> u64 {
     let now = std::time::SystemTime::now();
     let duration_since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap();
     duration_since_epoch.as_millis() as u64
 }
 
+/// The error of creating a doc whose ID is already taken, by a loaded doc or by one in
+/// the store.
+#[derive(Debug)]
+pub struct DocExists(String);
+
+impl std::error::Error for DocExists {}
+
+impl std::fmt::Display for DocExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Doc {} already exists.", self.0)
+    }
+}
+
+/// The error of a doc ID that is not a valid doc name.
+#[derive(Debug)]
+pub struct InvalidDocId(String);
+
+impl std::error::Error for InvalidDocId {}
+
+impl std::fmt::Display for InvalidDocId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid document name: {}", self.0)
+    }
+}
+
+impl From<InvalidDocId> for AppError {
+    fn from(e: InvalidDocId) -> Self {
+        AppError(StatusCode::BAD_REQUEST, e.into())
+    }
+}
+
+/// The error of creating a doc for a tenant that has reached its `max_docs` quota.
+#[derive(Debug)]
+pub struct DocQuotaExceeded {
+    tenant_id: String,
+    max_docs: usize,
+}
+
+impl std::error::Error for DocQuotaExceeded {}
+
+impl std::fmt::Display for DocQuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Tenant {} has reached its limit of {} docs.",
+            self.tenant_id, self.max_docs
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct AppError(StatusCode, anyhow::Error);
 impl std::error::Error for AppError {}
@@ -80,8 +172,38 @@ impl std::fmt::Display for AppError {
     }
 }
 
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadinessStatus {
+    /// The store is reachable and recent persists succeeded.
+    Ok,
+    /// The store is reachable, but the most recent persist of some docs failed.
+    Degraded,
+    /// The store could not be reached.
+    Unavailable,
+    /// The server is shutting down and not accepting new connections.
+    Draining,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ok: bool,
+    pub status: ReadinessStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Number of loaded docs with changes that have not been persisted yet.
+    #[serde(rename = "unpersistedDocs")]
+    pub unpersisted_docs: usize,
+    /// Number of docs whose most recent persist attempt failed.
+    #[serde(rename = "failedPersists")]
+    pub failed_persists: usize,
+}
+
 pub struct Server {
     docs: Arc<DashMap<String, DocWithSyncKv>>,
+    /// Locks that loads of the same doc take turns on, by doc ID. An entry exists while
+    /// a load of the doc is in progress or waiting.
+    loading: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
     doc_worker_tracker: TaskTracker,
     store: Option<Arc<Box<dyn Store>>>,
     checkpoint_freq: Duration,
@@ -94,9 +216,48 @@ pub struct Server {
     max_body_size: Option<usize>,
     /// Whether to skip garbage collection in Yrs documents.
     skip_gc: bool,
+    audit_log: AuditLog,
+    /// Whether doc tokens issued with a user ID record it. Servers older than this
+    /// feature cannot decode such tokens, so it is off unless enabled.
+    doc_token_user_ids: bool,
+    /// IDs of docs whose most recent persist attempt failed.
+    failed_persists: Arc<DashSet<String>>,
+    /// Unique per server instance, so that instances sharing a store do not
+    /// interfere with each other's readiness checks.
+    instance_id: String,
+    /// Whether the most recent background check of the store failed.
+    store_unavailable: Arc<AtomicBool>,
+    /// Cancelled when the server starts draining. This is a child of
+    /// `cancellation_token`, so it is also cancelled on shutdown.
+    drain_token: CancellationToken,
+    /// How long `drain` waits for dirty docs to be persisted.
+    drain_timeout: Duration,
+    /// The format doc snapshots are persisted in.
+    snapshot_format: SnapshotFormat,
+    /// Tenants whose docs are kept apart from each other, if the server hosts any.
+    tenants: Option<Arc<Tenants>>,
+    /// Number of open WebSocket connections, by tenant ID.
+    tenant_connections: Arc<DashMap<String, usize>>,
+    /// Number of docs of each tenant that has a doc quota, by tenant ID, once they
+    /// have been counted. Creating a doc of a tenant holds the tenant's lock.
+    tenant_doc_counts: DashMap<String, Arc<tokio::sync::Mutex<Option<usize>>>>,
+    /// Decides what doc tokens give access to, instead of `authenticator`.
+    authorizer: Option<Arc<dyn Authorizer>>,
+    /// Verifies JWTs from an identity provider, which are accepted alongside tokens
+    /// signed by `authenticator`.
+    jwt: Option<Arc<JwtVerifier>>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DrainReport {
+    /// IDs of docs that were persisted during the drain.
+    pub persisted: Vec<String>,
+    /// IDs of docs whose final persist failed or did not finish before the timeout.
+    pub failed: Vec<String>,
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         store: Option<Box<dyn Store>>,
         checkpoint_freq: Duration,
@@ -109,18 +270,94 @@ impl Server {
     ) -> Result<Self> {
         Ok(Self {
             docs: Arc::new(DashMap::new()),
+            loading: DashMap::new(),
             doc_worker_tracker: TaskTracker::new(),
             store: store.map(Arc::new),
             checkpoint_freq,
             authenticator,
             url_prefix,
-            cancellation_token,
+            cancellation_token: cancellation_token.clone(),
             doc_gc,
             max_body_size,
             skip_gc,
+            audit_log: AuditLog::default(),
+            doc_token_user_ids: false,
+            failed_persists: Arc::new(DashSet::new()),
+            instance_id: nanoid::nanoid!(),
+            store_unavailable: Arc::new(AtomicBool::new(false)),
+            drain_token: cancellation_token.child_token(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            snapshot_format: SnapshotFormat::Legacy,
+            tenants: None,
+            tenant_connections: Arc::new(DashMap::new()),
+            tenant_doc_counts: DashMap::new(),
+            authorizer: None,
+            jwt: None,
         })
     }
 
+    pub fn with_drain_timeout(self, drain_timeout: Duration) -> Self {
+        Self {
+            drain_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_snapshot_format(self, snapshot_format: SnapshotFormat) -> Self {
+        Self {
+            snapshot_format,
+            ..self
+        }
+    }
+
+    /// Hosts `tenants` in addition to the docs that belong to no tenant. Tokens signed
+    /// with a tenant's key only give access to that tenant's docs. The server's own
+    /// authenticator is still used for server-wide endpoints, so it should be set.
+    pub fn with_tenants(self, tenants: Tenants) -> Self {
+        Self {
+            tenants: Some(Arc::new(tenants)),
+            ..self
+        }
+    }
+
+    /// Asks `authorizer` what the tokens that clients connect with give access to,
+    /// instead of verifying them as doc tokens. Server tokens are still accepted. Once
+    /// this is set, server endpoints require a server token, so without an
+    /// authenticator they refuse every request.
+    pub fn with_authorizer(self, authorizer: Arc<dyn Authorizer>) -> Self {
+        Self {
+            authorizer: Some(authorizer),
+            ..self
+        }
+    }
+
+    /// Accepts JWTs that `jwt` verifies as server and doc tokens, in addition to the
+    /// authenticator's own tokens. Once this is set, requests without a valid token are
+    /// refused even if the server has no authenticator.
+    pub fn with_jwt_verifier(self, jwt: Arc<JwtVerifier>) -> Self {
+        Self {
+            jwt: Some(jwt),
+            ..self
+        }
+    }
+
+    /// Records the `userId` of a token request in the doc token, so that it shows up
+    /// in the audit log. Only enable this once every server that verifies the tokens
+    /// supports them.
+    pub fn with_doc_token_user_ids(self, doc_token_user_ids: bool) -> Self {
+        Self {
+            doc_token_user_ids,
+            ..self
+        }
+    }
+
+    pub fn with_audit_sink(self, sink: Arc<dyn AuditSink>) -> Self {
+        Self {
+            audit_log: AuditLog::new(sink),
+            ..self
+        }
+    }
+
     pub async fn doc_exists(&self, doc_id: &str) -> bool {
         if self.docs.contains_key(doc_id) {
             return true;
@@ -143,23 +380,84 @@ impl Server {
     }
 
     pub async fn load_doc(&self, doc_id: &str) -> Result<()> {
+        self.load_doc_with_update(doc_id, None).await
+    }
+
+    /// Creates a doc whose state is the v1 update `update`. The update is applied and
+    /// persisted before the doc is visible to other requests. Fails with `DocExists` if
+    /// the doc is loaded or in the store. If another server creates the doc at the same
+    /// time, the later write fails with `DocExists` only if the store supports
+    /// conditional writes (see `Store::set_if`); otherwise it overwrites the doc.
+    pub async fn create_doc_with_update(&self, doc_id: &str, update: &[u8]) -> Result<()> {
+        self.load_doc_with_update(doc_id, Some(update)).await?;
+        tracing::info!(doc_id=?doc_id, "Created doc");
+        Ok(())
+    }
+
+    /// Loads a doc, or with `update`, creates it. Loads of the same doc take turns, so
+    /// that a doc is loaded only once and two requests cannot both create it.
+    async fn load_doc_with_update(&self, doc_id: &str, update: Option<&[u8]>) -> Result<()> {
+        let lock = self.loading.entry(doc_id.to_owned()).or_default().clone();
+        let result = {
+            let _guard = lock.lock().await;
+            match (self.docs.contains_key(doc_id), update) {
+                (true, Some(_)) => Err(DocExists(doc_id.to_owned()).into()),
+                (true, None) => Ok(()),
+                (false, update) => self.load_doc_locked(doc_id, update).await,
+            }
+        };
+        // Loads that are still waiting hold a reference too, and remove the entry once
+        // they are done.
+        self.loading
+            .remove_if(doc_id, |_, lock| Arc::strong_count(lock) == 2);
+        result
+    }
+
+    async fn load_doc_locked(&self, doc_id: &str, update: Option<&[u8]>) -> Result<()> {
         let (send, recv) = channel(1024);
 
         let dwskv = DocWithSyncKv::new(
             doc_id,
             self.store.clone(),
             move || {
-                send.try_send(()).unwrap();
+                // Carry the trace context of whoever dirtied the doc, so that the
+                // resulting store write shows up in the same trace.
+                send.try_send(tracing::Span::current().context()).unwrap();
             },
             self.skip_gc,
+            self.snapshot_format,
         )
         .await?;
 
-        dwskv
-            .sync_kv()
-            .persist()
-            .await
-            .map_err(|e| anyhow!("Error persisting: {:?}", e))?;
+        // A doc that is not in the store yet is being created, including when it is
+        // only loaded, and counts against its tenant's doc quota.
+        let new_doc = dwskv.sync_kv().is_empty();
+        if update.is_some() && !new_doc {
+            return Err(DocExists(doc_id.to_owned()).into());
+        }
+        let tenant_doc_count = match new_doc {
+            true => self.reserve_tenant_doc(doc_id).await?,
+            false => None,
+        };
+
+        if let Some(update) = update {
+            dwskv.apply_update(update)?;
+
+            // The snapshot is only written if the doc is still absent from the store, so
+            // that of two servers creating the doc at once, one fails.
+            dwskv.sync_kv().persist_new().await.map_err(|e| {
+                match e.downcast_ref::<StoreError>() {
+                    Some(StoreError::Conflict(_)) => DocExists(doc_id.to_owned()).into(),
+                    _ => anyhow!("Error persisting: {:?}", e),
+                }
+            })?;
+        } else {
+            dwskv
+                .sync_kv()
+                .persist()
+                .await
+                .map_err(|e| anyhow!("Error persisting: {:?}", e))?;
+        }
 
         {
             let sync_kv = dwskv.sync_kv();
@@ -175,8 +473,9 @@ impl Server {
                     checkpoint_freq,
                     doc_id.clone(),
                     cancellation_token.clone(),
+                    self.failed_persists.clone(),
                 )
-                .instrument(span!(Level::INFO, "save_loop", doc_id=?doc_id)),
+                .instrument(span!(parent: None, Level::INFO, "save_loop", doc_id=?doc_id)),
             );
 
             if self.doc_gc {
@@ -187,15 +486,57 @@ impl Server {
                         checkpoint_freq,
                         cancellation_token,
                     )
-                    .instrument(span!(Level::INFO, "gc_loop", doc_id=?doc_id)),
+                    .instrument(span!(parent: None, Level::INFO, "gc_loop", doc_id=?doc_id)),
                 );
             }
         }
 
         self.docs.insert(doc_id.to_string(), dwskv);
+        if let Some(mut count) = tenant_doc_count {
+            *count = count.map(|count| count + 1);
+        }
         Ok(())
     }
 
+    /// Checks that the tenant of the new doc `doc_key`, if it has a doc quota, may
+    /// create another doc. The returned guard holds the tenant's doc count, which the
+    /// caller increments once the doc is created. The count is read from the store
+    /// the first time and kept in memory after that. Docs that were loaded but never
+    /// persisted count while loaded, so it is recounted before a create is refused.
+    async fn reserve_tenant_doc(
+        &self,
+        doc_key: &str,
+    ) -> Result<Option<OwnedMutexGuard<Option<usize>>>> {
+        let Some(tenant) = self
+            .tenants
+            .as_ref()
+            .and_then(|tenants| tenants.for_doc_key(doc_key))
+        else {
+            return Ok(None);
+        };
+        let Some(max_docs) = tenant.quotas().max_docs else {
+            return Ok(None);
+        };
+
+        let lock = self
+            .tenant_doc_counts
+            .entry(tenant.id().to_owned())
+            .or_default()
+            .clone();
+        let mut count = lock.lock_owned().await;
+        if count.is_none_or(|count| count >= max_docs) {
+            *count = Some(self.doc_ids(Some(tenant)).await?.len());
+        }
+        if count.is_some_and(|count| count >= max_docs) {
+            return Err(DocQuotaExceeded {
+                tenant_id: tenant.id().to_owned(),
+                max_docs,
+            }
+            .into());
+        }
+        Ok(Some(count))
+    }
+
     async fn doc_gc_worker(
         docs: Arc<DashMap<String, DocWithSyncKv>>,
         doc_id: String,
@@ -239,17 +580,23 @@ impl Server {
     }
 
     async fn doc_persistence_worker(
-        mut recv: Receiver<()>,
+        mut recv: Receiver<opentelemetry::Context>,
         sync_kv: Arc<SyncKv>,
         checkpoint_freq: Duration,
         doc_id: String,
         cancellation_token: CancellationToken,
+        failed_persists: Arc<DashSet<String>>,
     ) {
         let mut last_save = std::time::Instant::now();
+        let mut dirty_context = None;
 
         loop {
             let is_done = tokio::select! {
-                v = recv.recv() => v.is_none(),
+                v = recv.recv() => {
+                    let is_done = v.is_none();
+                    dirty_context = v;
+                    is_done
+                }
                 _ = cancellation_token.cancelled() => true,
                 _ = tokio::time::sleep(checkpoint_freq) => {
                     sync_kv.is_shutdown()
@@ -273,6 +620,7 @@ impl Server {
                             if v.is_none() {
                                 break;
                             }
+                            dirty_context = dirty_context.or(v);
                         }
                         _ = cancellation_token.cancelled() => {
                             tracing::info!("Received cancellation while throttling.");
@@ -284,10 +632,16 @@ impl Server {
                 }
             }
             tracing::info!("Persisting.");
-            if let Err(e) = sync_kv.persist().await {
+            let persist_span = span!(Level::INFO, "persist");
+            if let Some(context) = dirty_context.take() {
+                let _ = persist_span.set_parent(context);
+            }
+            if let Err(e) = sync_kv.persist().instrument(persist_span).await {
                 tracing::error!(?e, "Error persisting.");
+                failed_persists.insert(doc_id.clone());
             } else {
                 tracing::info!("Done persisting.");
+                failed_persists.remove(&doc_id);
             }
             last_save = std::time::Instant::now();
 
@@ -295,13 +649,185 @@ impl Server {
                 break;
             }
         }
+        failed_persists.remove(&doc_id);
         tracing::info!("Terminating loop for {}", doc_id);
     }
 
+    /// Verify that the store is reachable by writing a sentinel value, reading
+    /// it back, and removing it.
+    pub async fn check_store(&self) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+
+        // Doc IDs cannot contain '.', so this cannot collide with a document.
+        let key = format!(".y-sweet/readiness/{}", self.instance_id);
+        let value = current_time_epoch_millis().to_be_bytes().to_vec();
+
+        let round_trip = async {
+            store.set(&key, value.clone()).await?;
+            let read = store.get(&key).await?;
+            if read.as_deref() != Some(value.as_slice()) {
+                return Err(anyhow!(
+                    "Value read from store did not match value written."
+                ));
+            }
+            if let Err(e) = store.remove(&key).await {
+                tracing::warn!(?e, "Failed to remove readiness check key from store.");
+            }
+            Ok(())
+        };
+
+        tokio::time::timeout(STORE_CHECK_TIMEOUT, round_trip)
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to store."))?
+    }
+
+    /// Checks the store and records the result for `readiness`.
+    pub async fn update_store_health(&self) {
+        let result = self.check_store().await;
+        if let Err(e) = &result {
+            tracing::warn!(?e, "Store check failed.");
+        }
+        self.store_unavailable
+            .store(result.is_err(), Ordering::Relaxed);
+    }
+
+    /// Checks the store every `STORE_CHECK_INTERVAL` until the server shuts down, so
+    /// that readiness probes do not each make requests to the store.
+    async fn check_store_periodically(&self) {
+        if self.store.is_none() {
+            return;
+        }
+        loop {
+            self.update_store_health().await;
+            tokio::select! {
+                _ = tokio::time::sleep(STORE_CHECK_INTERVAL) => {}
+                _ = self.cancellation_token.cancelled() => return,
+            }
+        }
+    }
+
+    /// Reports readiness from the result of the most recent background store check,
+    /// without contacting the store.
+    pub fn readiness(&self) -> Readiness {
+        let unpersisted_docs = self
+            .docs
+            .iter()
+            .filter(|doc| doc.sync_kv().is_dirty())
+            .count();
+        let failed_persists = self.failed_persists.len();
+
+        let (status, error) = if self.is_draining() {
+            (ReadinessStatus::Draining, None)
+        } else if self.store_unavailable.load(Ordering::Relaxed) {
+            (
+                ReadinessStatus::Unavailable,
+                Some("Store is unavailable.".to_string()),
+            )
+        } else if failed_persists > 0 {
+            (ReadinessStatus::Degraded, None)
+        } else {
+            (ReadinessStatus::Ok, None)
+        };
+
+        Readiness {
+            ok: matches!(status, ReadinessStatus::Ok),
+            status,
+            error,
+            unpersisted_docs,
+            failed_persists,
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain_token.is_cancelled()
+    }
+
+    /// Refuses requests that would change docs once the server is draining, since
+    /// those changes might not be persisted before it exits.
+    fn check_not_draining(&self) -> Result<(), AppError> {
+        if self.is_draining() {
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                anyhow!("Server is draining."),
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Stop accepting new connections, close existing WebSocket connections with a
+    /// "going away" close code so that clients reconnect elsewhere, and persist every
+    /// dirty doc. Gives up on any persist that has not finished within the drain timeout.
+    pub async fn drain(&self) -> DrainReport {
+        self.drain_until(Instant::now() + self.drain_timeout).await
+    }
+
+    /// Like `drain`, but gives up on persists that have not finished by `deadline`.
+    pub async fn drain_until(&self, deadline: Instant) -> DrainReport {
+        tracing::info!("Draining.");
+        self.drain_token.cancel();
+
+        let dirty_docs: Vec<(String, Arc<SyncKv>)> = self
+            .docs
+            .iter()
+            .filter(|doc| doc.sync_kv().is_dirty())
+            .map(|doc| (doc.key().clone(), doc.sync_kv()))
+            .collect();
+
+        let mut pending: HashSet<String> = dirty_docs
+            .iter()
+            .map(|(doc_id, _)| doc_id.clone())
+            .collect();
+        let mut persists: FuturesUnordered<_> = dirty_docs
+            .into_iter()
+            .map(|(doc_id, sync_kv)| async move {
+                let result = sync_kv.persist().await.map_err(|e| e.to_string());
+                (doc_id, result)
+            })
+            .collect();
+
+        let mut report = DrainReport::default();
+        let deadline = tokio::time::sleep_until(deadline);
+        tokio::pin!(deadline);
+
+        while !pending.is_empty() {
+            tokio::select! {
+                Some((doc_id, result)) = persists.next() => {
+                    pending.remove(&doc_id);
+                    match result {
+                        Ok(()) => report.persisted.push(doc_id),
+                        Err(e) => {
+                            tracing::error!(?doc_id, ?e, "Final persist failed.");
+                            report.failed.push(doc_id);
+                        }
+                    }
+                }
+                _ = &mut deadline => {
+                    for doc_id in pending.drain() {
+                        tracing::error!(?doc_id, "Final persist did not finish before drain timeout.");
+                        report.failed.push(doc_id);
+                    }
+                }
+            }
+        }
+
+        tracing::info!(
+            persisted = report.persisted.len(),
+            failed = report.failed.len(),
+            "Done draining."
+        );
+        report
+    }
+
     pub async fn get_or_create_doc(
         &self,
         doc_id: &str,
-    ) -> Result<MappedRef<String, DocWithSyncKv, DocWithSyncKv>> {
+    ) -> Result<MappedRef<'_, String, DocWithSyncKv, DocWithSyncKv>> {
         if !self.docs.contains_key(doc_id) {
             tracing::info!(doc_id=?doc_id, "Loading doc");
             self.load_doc(doc_id).await?;
@@ -314,22 +840,272 @@ impl Server {
             .map(|d| d))
     }
 
+    /// Creates a doc that starts as a copy of `doc_id`, or of its state at `snapshot`,
+    /// and returns its ID. The copy is made from the loaded source doc, so it includes
+    /// changes that have not been persisted yet. Like `create_doc_with_update`, fails
+    /// with `DocExists` if the new doc already exists.
+    pub async fn fork_doc(
+        &self,
+        doc_id: &str,
+        new_doc_id: Option<String>,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<String> {
+        let update = {
+            let source = self.get_or_create_doc(doc_id).await?;
+            match snapshot {
+                Some(snapshot) => source.as_update_at(snapshot)?,
+                None => source.as_update(),
+            }
+        };
+
+        let new_doc_id = new_doc_id.unwrap_or_else(|| nanoid::nanoid!());
+        self.create_doc_with_update(&new_doc_id, &update).await?;
+        tracing::info!(doc_id, new_doc_id, "Forked doc");
+        Ok(new_doc_id)
+    }
+
+    /// Reads the metadata of a doc from the store, if it has any.
+    pub async fn doc_metadata(&self, doc_id: &str) -> Result<Option<DocMetadata>> {
+        let store = self
+            .store
+            .as_ref()
+            .ok_or_else(|| anyhow!("Doc metadata requires a store."))?;
+        let key = format!("{}{}", doc_id, DOC_METADATA_SUFFIX);
+        match store.get(&key).await? {
+            Some(metadata) => Ok(Some(serde_json::from_slice(&metadata)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the metadata of a doc in the store.
+    pub async fn set_doc_metadata(&self, doc_id: &str, metadata: &DocMetadata) -> Result<()> {
+        let store = self
+            .store
+            .as_ref()
+            .ok_or_else(|| anyhow!("Doc metadata requires a store."))?;
+        let key = format!("{}{}", doc_id, DOC_METADATA_SUFFIX);
+        store.set(&key, serde_json::to_vec(metadata)?).await?;
+        Ok(())
+    }
+
+    /// IDs of the docs of `tenant` that are in the store or loaded, in order. Without a
+    /// tenant, these are the docs that belong to no tenant.
+    pub async fn doc_ids(&self, tenant: Option<&Tenant>) -> Result<BTreeSet<String>> {
+        let prefix = tenant.map_or("", |tenant| tenant.store_prefix());
+        let mut doc_keys: BTreeSet<String> = self
+            .docs
+            .iter()
+            .map(|doc| doc.key().clone())
+            .filter(|doc_key| doc_key.starts_with(prefix))
+            .collect();
+        if let Some(store) = &self.store {
+            doc_keys.extend(list_doc_ids_with_prefix(store.as_ref().as_ref(), prefix).await?);
+        }
+
+        Ok(doc_keys
+            .into_iter()
+            .filter_map(|doc_key| match tenant {
+                Some(tenant) => tenant.doc_id(&doc_key).map(str::to_owned),
+                None if self.tenants.is_some() && doc_key.contains('/') => None,
+                None => Some(doc_key),
+            })
+            .collect())
+    }
+
+    /// Lists a page of up to `limit` docs of `tenant` (see `doc_ids`) with their
+    /// metadata, starting after the doc `cursor`. Docs are listed in the order of their
+    /// snapshot keys in the store. With `tag`, only docs whose metadata has that tag are
+    /// listed, so a page can hold fewer docs even if there are more.
+    pub async fn list_docs(
+        &self,
+        tenant: Option<&Tenant>,
+        tag: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListDocsResponse> {
+        let prefix = tenant.map_or("", |tenant| tenant.store_prefix());
+        let snapshot_key = |doc_key: &str| format!("{}{}", doc_key, DOC_SNAPSHOT_SUFFIX);
+        // The cursor only bounds the listing and is never read as a key, so it need not
+        // be a valid doc name.
+        let cursor_key = cursor.map(|cursor| format!("{}{}", prefix, cursor));
+
+        let (mut doc_keys, store_exhausted) = match &self.store {
+            Some(store) => {
+                let doc_keys = list_doc_ids_page(
+                    store.as_ref().as_ref(),
+                    prefix,
+                    cursor_key.as_deref(),
+                    limit,
+                )
+                .await?;
+                let store_exhausted = doc_keys.len() < limit;
+                (doc_keys, store_exhausted)
+            }
+            None => (Vec::new(), true),
+        };
+
+        // Loaded docs may not have been persisted yet. Those that fall within the page
+        // of the store are listed with it.
+        let after = cursor_key.as_deref().map(snapshot_key);
+        let until = match store_exhausted {
+            true => None,
+            false => doc_keys.last().map(|doc_key| snapshot_key(doc_key)),
+        };
+        doc_keys.extend(
+            self.docs
+                .iter()
+                .map(|doc| doc.key().clone())
+                .filter(|doc_key| doc_key.starts_with(prefix))
+                .filter(|doc_key| {
+                    let key = snapshot_key(doc_key);
+                    after.as_ref().is_none_or(|after| &key > after)
+                        && until.as_ref().is_none_or(|until| &key <= until)
+                }),
+        );
+        doc_keys.sort_by_cached_key(|doc_key| snapshot_key(doc_key));
+        doc_keys.dedup();
+
+        let next_cursor = if !store_exhausted || doc_keys.len() > limit {
+            doc_keys.truncate(limit);
+            doc_keys.last().map(|doc_key| match tenant {
+                Some(tenant) => tenant.doc_id(doc_key).unwrap_or(doc_key).to_owned(),
+                None => doc_key.clone(),
+            })
+        } else {
+            None
+        };
+        let doc_ids = doc_keys.into_iter().filter_map(|doc_key| match tenant {
+            Some(tenant) => tenant
+                .doc_id(&doc_key)
+                .map(|doc_id| (doc_id.to_owned(), doc_key.clone())),
+            None if self.tenants.is_some() && doc_key.contains('/') => None,
+            None => Some((doc_key.clone(), doc_key)),
+        });
+
+        let docs: Vec<DocListing> = futures::stream::iter(doc_ids)
+            .map(|(doc_id, doc_key)| async move {
+                let metadata = match &self.store {
+                    Some(_) => self.doc_metadata(&doc_key).await?,
+                    None => None,
+                };
+                Ok::<_, anyhow::Error>(DocListing { doc_id, metadata })
+            })
+            .buffered(LIST_DOCS_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let docs = match tag {
+            Some(tag) => docs
+                .into_iter()
+                .filter(|doc| {
+                    doc.metadata
+                        .as_ref()
+                        .is_some_and(|metadata| metadata.tags.iter().any(|t| t == tag))
+                })
+                .collect(),
+            None => docs,
+        };
+        Ok(ListDocsResponse { docs, next_cursor })
+    }
+
+    /// Compacts the stored state of a doc (see `SyncKv::compact`) and, unless this is
+    /// a dry run, persists the result. If the doc is loaded with garbage collection
+    /// disabled, its in-memory copy keeps its deleted content until it is reloaded.
+    pub async fn compact_doc(&self, doc_id: &str, dry_run: bool) -> Result<CompactionReport> {
+        let sync_kv = self.get_or_create_doc(doc_id).await?.sync_kv();
+        let report = sync_kv.compact(dry_run)?;
+        if !dry_run {
+            sync_kv
+                .persist()
+                .await
+                .map_err(|e| anyhow!("Error persisting: {:?}", e))?;
+        }
+        tracing::info!(
+            doc_id,
+            size_before = report.size_before,
+            size_after = report.size_after,
+            dry_run,
+            "Compacted doc"
+        );
+        Ok(report)
+    }
+
     pub fn check_auth(
         &self,
         auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
     ) -> Result<(), AppError> {
-        if let Some(auth) = &self.authenticator {
-            if let Some(TypedHeader(headers::Authorization(bearer))) = auth_header {
-                if let Ok(()) =
-                    auth.verify_server_token(bearer.token(), current_time_epoch_millis())
+        if self.authenticator.is_none() && self.jwt.is_none() && self.authorizer.is_none() {
+            return Ok(());
+        }
+        match auth_header {
+            Some(TypedHeader(headers::Authorization(bearer)))
+                if self.is_server_token(bearer.token()) =>
+            {
+                Ok(())
+            }
+            _ => Err((StatusCode::UNAUTHORIZED, anyhow!("Unauthorized.")))?,
+        }
+    }
+
+    /// Whether `token` is a valid server token, of the authenticator or as a JWT.
+    fn is_server_token(&self, token: &str) -> bool {
+        match &self.jwt {
+            Some(jwt) if JwtVerifier::is_jwt(token) => jwt.verify_server_token(token).is_ok(),
+            _ => self.authenticator.as_ref().is_some_and(|authenticator| {
+                authenticator
+                    .verify_server_token(token, current_time_epoch_millis())
+                    .is_ok()
+            }),
+        }
+    }
+
+    /// Like `check_auth`, but also accepts the server token of a tenant, which is
+    /// returned. Endpoints that use this act on the docs of the returned tenant.
+    pub fn check_tenant_auth(
+        &self,
+        auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    ) -> Result<Option<Arc<Tenant>>, AppError> {
+        if let (Some(tenants), Some(TypedHeader(headers::Authorization(bearer)))) =
+            (&self.tenants, &auth_header)
+        {
+            if let Some(tenant) = tenants.for_token(bearer.token()) {
+                return match tenant
+                    .authenticator()
+                    .verify_server_token(bearer.token(), current_time_epoch_millis())
                 {
-                    return Ok(());
-                }
+                    Ok(()) => Ok(Some(tenant.clone())),
+                    Err(_) => Err((StatusCode::UNAUTHORIZED, anyhow!("Unauthorized.")))?,
+                };
+            }
+        }
+        self.check_auth(auth_header)?;
+        Ok(None)
+    }
+
+    /// Counts a WebSocket connection against the connection quota of `tenant` until
+    /// the returned guard is dropped.
+    fn open_tenant_connection(&self, tenant: &Tenant) -> Result<TenantConnection, AppError> {
+        let mut count = self
+            .tenant_connections
+            .entry(tenant.id().to_string())
+            .or_insert(0);
+        if let Some(max_connections) = tenant.quotas().max_connections {
+            if *count >= max_connections {
+                Err((
+                    StatusCode::TOO_MANY_REQUESTS,
+                    anyhow!(
+                        "Tenant {} has reached its limit of {} connections.",
+                        tenant.id(),
+                        max_connections
+                    ),
+                ))?
             }
-            Err((StatusCode::UNAUTHORIZED, anyhow!("Unauthorized.")))?
-        } else {
-            Ok(())
         }
+        *count += 1;
+        Ok(TenantConnection {
+            tenant_connections: self.tenant_connections.clone(),
+            tenant_id: tenant.id().to_string(),
+        })
     }
 
     pub async fn redact_error_middleware(req: Request, next: Next) -> impl IntoResponse {
@@ -345,6 +1121,8 @@ impl Server {
     pub fn routes(self: &Arc<Self>) -> Router {
         Router::new()
             .route("/ready", get(ready))
+            .route("/live", get(live))
+            .route("/drain", post(drain))
             .route("/check_store", post(check_store))
             .route("/check_store", get(check_store_deprecated))
             .route("/doc/ws/:doc_id", get(handle_socket_upgrade_deprecated))
@@ -354,6 +1132,14 @@ impl Server {
             .route("/doc/:doc_id/update", post(update_doc_deprecated))
             .route("/d/:doc_id/as-update", get(get_doc_as_update))
             .route("/d/:doc_id/update", post(update_doc))
+            .route("/d/:doc_id/stats", get(get_doc_stats))
+            .route("/d/:doc_id/compact", post(compact_doc))
+            .route("/d/:doc_id/fork", post(fork_doc))
+            .route(
+                "/d/:doc_id/metadata",
+                get(get_doc_metadata).put(put_doc_metadata),
+            )
+            .route("/docs", get(list_docs))
             .route(
                 "/d/:doc_id/ws/:doc_id2",
                 get(handle_socket_upgrade_full_path),
@@ -389,6 +1175,13 @@ impl Server {
             app.layer(middleware::from_fn(Self::redact_error_middleware))
         };
 
+        app = app.layer(middleware::from_fn(trace_context_middleware));
+
+        tokio::spawn({
+            let server = self.clone();
+            async move { server.check_store_periodically().await }
+        });
+
         axum::serve(listener, app.into_make_service())
             .with_graceful_shutdown(async move { token.cancelled().await })
             .await?;
@@ -399,30 +1192,136 @@ impl Server {
         Ok(())
     }
 
-    pub async fn serve(self, listener: TcpListener, redact_errors: bool) -> Result<()> {
-        let s = Arc::new(self);
-        let routes = s.routes();
-        s.serve_internal(listener, redact_errors, routes).await
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, redact_errors: bool) -> Result<()> {
+        let routes = self.routes();
+        self.serve_internal(listener, redact_errors, routes).await
     }
 
-    pub async fn serve_doc(self, listener: TcpListener, redact_errors: bool) -> Result<()> {
-        let s = Arc::new(self);
-        let routes = s.single_doc_routes();
-        s.serve_internal(listener, redact_errors, routes).await
+    pub async fn serve_doc(
+        self: Arc<Self>,
+        listener: TcpListener,
+        redact_errors: bool,
+    ) -> Result<()> {
+        let routes = self.single_doc_routes();
+        self.serve_internal(listener, redact_errors, routes).await
     }
 
-    fn verify_doc_token(&self, token: Option<&str>, doc: &str) -> Result<Authorization, AppError> {
-        if let Some(authenticator) = &self.authenticator {
-            if let Some(token) = token {
-                let authorization = authenticator
-                    .verify_doc_token(token, doc, current_time_epoch_millis())
-                    .map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
-                Ok(authorization)
-            } else {
-                Err((StatusCode::UNAUTHORIZED, anyhow!("No token provided.")))?
-            }
-        } else {
+    /// Decides what `token` gives access to, by asking the authorizer if the server has
+    /// one, and otherwise by verifying it as a doc token.
+    async fn authorize_doc(&self, token: Option<&str>, doc: &str) -> Result<DocAccess, AppError> {
+        let Some(authorizer) = &self.authorizer else {
+            return self.verify_doc_token(token, doc);
+        };
+
+        let subject = AuditSubject::new(doc, None, None);
+        let result = if token.is_some_and(|token| self.is_server_token(token)) {
             Ok(Authorization::Full)
+        } else {
+            authorizer.authorize(doc, token).await
+        };
+
+        match result {
+            Ok(authorization) => {
+                self.audit_log
+                    .record(&subject, AuditEventKind::TokenVerified { authorization });
+                Ok(DocAccess {
+                    authorization,
+                    subject,
+                    tenant: None,
+                })
+            }
+            Err(e) => {
+                self.audit_log.record(
+                    &subject,
+                    AuditEventKind::TokenRejected {
+                        reason: e.to_string(),
+                    },
+                );
+                let status = match e {
+                    AuthorizerError::Denied(_) => StatusCode::UNAUTHORIZED,
+                    AuthorizerError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                };
+                Err((status, e))?
+            }
+        }
+    }
+
+    fn verify_doc_token(&self, token: Option<&str>, doc: &str) -> Result<DocAccess, AppError> {
+        if let (Some(jwt), Some(token)) = (&self.jwt, token) {
+            if JwtVerifier::is_jwt(token) {
+                return self.doc_access(doc, None, None, jwt.verify_doc_token(token, doc));
+            }
+        }
+
+        // A token that names a tenant is verified with the tenant's key, and gives
+        // access to the tenant's doc, never to another tenant's doc with the same ID.
+        let tenant = self
+            .tenants
+            .as_ref()
+            .zip(token)
+            .and_then(|(tenants, token)| tenants.for_token(token))
+            .cloned();
+        let authenticator = match &tenant {
+            Some(tenant) => Some(tenant.authenticator()),
+            None => self.authenticator.as_ref(),
+        };
+
+        if authenticator.is_none() && self.jwt.is_none() {
+            return Ok(DocAccess {
+                authorization: Authorization::Full,
+                subject: AuditSubject::new(doc, None, None),
+                tenant: None,
+            });
+        }
+
+        let key_id = authenticator.and_then(|authenticator| authenticator.key_id());
+        let Some(token) = token else {
+            self.audit_log.record(
+                &AuditSubject::new(doc, key_id, None),
+                AuditEventKind::TokenRejected {
+                    reason: "No token provided.".to_string(),
+                },
+            );
+            Err((StatusCode::UNAUTHORIZED, anyhow!("No token provided.")))?
+        };
+        // Without an authenticator, only JWTs are accepted, and this is not one.
+        let result = match authenticator {
+            Some(authenticator) => {
+                authenticator.verify_doc_token_with_user_id(token, doc, current_time_epoch_millis())
+            }
+            None => Err(AuthError::InvalidToken),
+        };
+        self.doc_access(doc, key_id, tenant.clone(), result)
+    }
+
+    /// Audits the result of verifying a doc token, and turns it into a `DocAccess`.
+    fn doc_access(
+        &self,
+        doc: &str,
+        key_id: Option<&str>,
+        tenant: Option<Arc<Tenant>>,
+        result: Result<(Authorization, Option<String>), AuthError>,
+    ) -> Result<DocAccess, AppError> {
+        match result {
+            Ok((authorization, user_id)) => {
+                let subject = AuditSubject::new(doc, key_id, user_id);
+                self.audit_log
+                    .record(&subject, AuditEventKind::TokenVerified { authorization });
+                Ok(DocAccess {
+                    authorization,
+                    subject,
+                    tenant,
+                })
+            }
+            Err(e) => {
+                self.audit_log.record(
+                    &AuditSubject::new(doc, key_id, None),
+                    AuditEventKind::TokenRejected {
+                        reason: e.to_string(),
+                    },
+                );
+                Err((StatusCode::UNAUTHORIZED, e))?
+            }
         }
     }
 
@@ -440,25 +1339,245 @@ struct HandlerParams {
     token: Option<String>,
 }
 
-async fn get_doc_as_update(
-    State(server_state): State<Arc<Server>>,
-    Path(doc_id): Path<String>,
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-) -> Result<Response, AppError> {
-    // All authorization types allow reading the document.
+/// What a verified doc token gives access to.
+struct DocAccess {
+    authorization: Authorization,
+    subject: AuditSubject,
+    /// The tenant the doc belongs to, if any.
+    tenant: Option<Arc<Tenant>>,
+}
+
+/// An open WebSocket connection of a tenant, which counts against the tenant's
+/// connection quota until it is dropped.
+#[derive(Debug)]
+struct TenantConnection {
+    tenant_connections: Arc<DashMap<String, usize>>,
+    tenant_id: String,
+}
+
+impl Drop for TenantConnection {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.tenant_connections.get_mut(&self.tenant_id) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// The key under which a doc is kept in the store and in memory, which includes the
+/// store prefix of its tenant, if any. Fails unless `doc_id` is a valid doc name, so
+/// that a doc ID from a request cannot name a key outside of the doc's (or its
+/// tenant's) part of the store.
+fn doc_key(tenant: Option<&Tenant>, doc_id: &str) -> Result<String, InvalidDocId> {
+    match tenant {
+        Some(tenant) => tenant.doc_key(doc_id),
+        None => validate_doc_name(doc_id).then(|| doc_id.to_string()),
+    }
+    .ok_or_else(|| InvalidDocId(doc_id.to_string()))
+}
+
+async fn get_doc_as_update(
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    // All authorization types allow reading the document.
     let token = get_token_from_header(auth_header);
-    let _ = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    let access = server_state
+        .authorize_doc(token.as_deref(), &doc_id)
+        .await?;
 
     let dwskv = server_state
-        .get_or_create_doc(&doc_id)
+        .get_or_create_doc(&doc_key(access.tenant.as_deref(), &doc_id)?)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        .map_err(doc_creation_error)?;
 
     let update = dwskv.as_update();
     tracing::debug!("update: {:?}", update);
     Ok(update.into_response())
 }
 
+async fn get_doc_stats(
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocStats>, AppError> {
+    let tenant = server_state.check_tenant_auth(auth_header)?;
+    let doc_key = doc_key(tenant.as_deref(), &doc_id)?;
+
+    if !server_state.doc_exists(&doc_key).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let dwskv = server_state
+        .get_or_create_doc(&doc_key)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    Ok(Json(dwskv.stats()))
+}
+
+async fn compact_doc(
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<CompactDocRequest>>,
+) -> Result<Json<CompactionReport>, AppError> {
+    let tenant = server_state.check_tenant_auth(auth_header)?;
+    server_state.check_not_draining()?;
+    let Json(CompactDocRequest { dry_run }) = body.unwrap_or_default();
+    let doc_key = doc_key(tenant.as_deref(), &doc_id)?;
+
+    if !server_state.doc_exists(&doc_key).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let report = server_state
+        .compact_doc(&doc_key, dry_run)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(report))
+}
+
+async fn fork_doc(
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<DocForkRequest>>,
+) -> Result<Json<NewDocResponse>, AppError> {
+    let tenant = server_state.check_tenant_auth(auth_header)?;
+    server_state.check_not_draining()?;
+    let Json(DocForkRequest {
+        doc_id: new_doc_id,
+        snapshot,
+    }) = body.unwrap_or_default();
+    let tenant = tenant.as_deref();
+
+    if !server_state.doc_exists(&doc_key(tenant, &doc_id)?).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let new_doc_id = match new_doc_id {
+        Some(new_doc_id) => {
+            if !validate_doc_name(&new_doc_id) {
+                Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document name")))?
+            }
+            new_doc_id
+        }
+        None => nanoid::nanoid!(),
+    };
+
+    let snapshot = if let Some(snapshot) = snapshot {
+        if !server_state.skip_gc {
+            Err((
+                StatusCode::BAD_REQUEST,
+                anyhow!("Forking from a snapshot requires garbage collection to be disabled."),
+            ))?
+        }
+        let snapshot = BASE64
+            .decode(snapshot.as_bytes())
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        let snapshot = Snapshot::decode_v1(&snapshot).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        Some(snapshot)
+    } else {
+        None
+    };
+
+    server_state
+        .fork_doc(
+            &doc_key(tenant, &doc_id)?,
+            Some(doc_key(tenant, &new_doc_id)?),
+            snapshot.as_ref(),
+        )
+        .await
+        .map_err(doc_creation_error)?;
+    Ok(Json(NewDocResponse { doc_id: new_doc_id }))
+}
+
+async fn get_doc_metadata(
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocMetadata>, AppError> {
+    let tenant = server_state.check_tenant_auth(auth_header)?;
+    check_metadata_supported(&server_state)?;
+    let doc_key = doc_key(tenant.as_deref(), &doc_id)?;
+
+    if !server_state.doc_exists(&doc_key).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let metadata = server_state
+        .doc_metadata(&doc_key)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(metadata.unwrap_or_default()))
+}
+
+async fn put_doc_metadata(
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(metadata): Json<DocMetadata>,
+) -> Result<Json<DocMetadata>, AppError> {
+    let tenant = server_state.check_tenant_auth(auth_header)?;
+    check_metadata_supported(&server_state)?;
+    server_state.check_not_draining()?;
+    let doc_key = doc_key(tenant.as_deref(), &doc_id)?;
+
+    if !server_state.doc_exists(&doc_key).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    server_state
+        .set_doc_metadata(&doc_key, &metadata)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(metadata))
+}
+
+fn check_metadata_supported(server_state: &Server) -> Result<(), AppError> {
+    if server_state.store.is_none() {
+        Err((
+            StatusCode::NOT_IMPLEMENTED,
+            anyhow!("Doc metadata requires a store."),
+        ))?
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Default)]
+struct ListDocsParams {
+    tag: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn list_docs(
+    State(server_state): State<Arc<Server>>,
+    Query(params): Query<ListDocsParams>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<ListDocsResponse>, AppError> {
+    let tenant = server_state.check_tenant_auth(auth_header)?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_DOCS_LIMIT);
+    if !(1..=MAX_LIST_DOCS_LIMIT).contains(&limit) {
+        Err((
+            StatusCode::BAD_REQUEST,
+            anyhow!("Limit must be between 1 and {}.", MAX_LIST_DOCS_LIMIT),
+        ))?
+    }
+
+    let response = server_state
+        .list_docs(
+            tenant.as_deref(),
+            params.tag.as_deref(),
+            params.cursor.as_deref(),
+            limit,
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(response))
+}
+
 async fn get_doc_as_update_deprecated(
     Path(doc_id): Path<String>,
     State(server_state): State<Arc<Server>>,
@@ -493,30 +1612,46 @@ async fn update_doc(
     body: Bytes,
 ) -> Result<Response, AppError> {
     let token = get_token_from_header(auth_header);
-    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
-    update_doc_inner(doc_id, server_state, authorization, body).await
+    let access = server_state
+        .authorize_doc(token.as_deref(), &doc_id)
+        .await?;
+    let doc_key = doc_key(access.tenant.as_deref(), &doc_id)?;
+    update_doc_inner(
+        doc_key,
+        server_state,
+        access.authorization,
+        access.subject,
+        body,
+    )
+    .await
 }
 
 async fn update_doc_inner(
-    doc_id: String,
+    doc_key: String,
     server_state: Arc<Server>,
     authorization: Authorization,
+    subject: AuditSubject,
     body: Bytes,
 ) -> Result<Response, AppError> {
     if !matches!(authorization, Authorization::Full) {
         return Err(AppError(StatusCode::FORBIDDEN, anyhow!("Unauthorized.")));
     }
+    server_state.check_not_draining()?;
 
     let dwskv = server_state
-        .get_or_create_doc(&doc_id)
+        .get_or_create_doc(&doc_key)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        .map_err(doc_creation_error)?;
 
     if let Err(err) = dwskv.apply_update(&body) {
         tracing::error!(?err, "Failed to apply update");
         return Err(AppError(StatusCode::INTERNAL_SERVER_ERROR, err));
     }
 
+    server_state
+        .audit_log
+        .record(&subject, AuditEventKind::HttpUpdate { size: body.len() });
+
     Ok(StatusCode::OK.into_response())
 }
 
@@ -529,31 +1664,58 @@ async fn update_doc_single(
     // the doc server is meant to be run in Plane, so we expect verified plane
     // headers to be used for authorization.
     let authorization = get_authorization_from_plane_header(headers)?;
-    update_doc_inner(doc_id, server_state, authorization, body).await
+    let subject = AuditSubject::new(&doc_id, None, None);
+    update_doc_inner(doc_id, server_state, authorization, subject, body).await
 }
 
 async fn handle_socket_upgrade(
     ws: WebSocketUpgrade,
     Path(doc_id): Path<String>,
-    authorization: Authorization,
+    access: DocAccess,
     State(server_state): State<Arc<Server>>,
 ) -> Result<Response, AppError> {
-    if !matches!(authorization, Authorization::Full) && !server_state.docs.contains_key(&doc_id) {
+    let DocAccess {
+        authorization,
+        subject,
+        tenant,
+    } = access;
+    let doc_key = doc_key(tenant.as_deref(), &doc_id)?;
+
+    server_state.check_not_draining()?;
+
+    if !matches!(authorization, Authorization::Full) && !server_state.docs.contains_key(&doc_key) {
         return Err(AppError(
             StatusCode::NOT_FOUND,
             anyhow!("Doc {} not found", doc_id),
         ));
     }
 
+    let tenant_connection = tenant
+        .as_deref()
+        .map(|tenant| server_state.open_tenant_connection(tenant))
+        .transpose()?;
+
     let dwskv = server_state
-        .get_or_create_doc(&doc_id)
+        .get_or_create_doc(&doc_key)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        .map_err(doc_creation_error)?;
     let awareness = dwskv.awareness();
-    let cancellation_token = server_state.cancellation_token.clone();
-
-    Ok(ws.on_upgrade(move |socket| {
-        handle_socket(socket, awareness, authorization, cancellation_token)
+    let open_connection = dwskv.open_connection();
+    let drain_token = server_state.drain_token.clone();
+    let audit_log = server_state.audit_log.clone();
+
+    Ok(ws.on_upgrade(move |socket| async move {
+        handle_socket(
+            socket,
+            awareness,
+            authorization,
+            drain_token,
+            audit_log,
+            subject,
+        )
+        .await;
+        drop(open_connection);
+        drop(tenant_connection);
     }))
 }
 
@@ -566,8 +1728,10 @@ async fn handle_socket_upgrade_deprecated(
     tracing::warn!(
         "/doc/ws/:doc_id is deprecated; call /doc/:doc_id/auth instead and use the returned URL."
     );
-    let authorization = server_state.verify_doc_token(params.token.as_deref(), &doc_id)?;
-    handle_socket_upgrade(ws, Path(doc_id), authorization, State(server_state)).await
+    let access = server_state
+        .authorize_doc(params.token.as_deref(), &doc_id)
+        .await?;
+    handle_socket_upgrade(ws, Path(doc_id), access, State(server_state)).await
 }
 
 async fn handle_socket_upgrade_full_path(
@@ -582,8 +1746,10 @@ async fn handle_socket_upgrade_full_path(
             anyhow!("For Yjs compatibility, the doc_id appears twice in the URL. It must be the same in both places, but we got {} and {}.", doc_id, doc_id2),
         ));
     }
-    let authorization = server_state.verify_doc_token(params.token.as_deref(), &doc_id)?;
-    handle_socket_upgrade(ws, Path(doc_id), authorization, State(server_state)).await
+    let access = server_state
+        .authorize_doc(params.token.as_deref(), &doc_id)
+        .await?;
+    handle_socket_upgrade(ws, Path(doc_id), access, State(server_state)).await
 }
 
 async fn handle_socket_upgrade_single(
@@ -603,15 +1769,24 @@ async fn handle_socket_upgrade_single(
     // the doc server is meant to be run in Plane, so we expect verified plane
     // headers to be used for authorization.
     let authorization = get_authorization_from_plane_header(headers)?;
-    handle_socket_upgrade(ws, Path(single_doc_id), authorization, State(server_state)).await
+    let access = DocAccess {
+        authorization,
+        subject: AuditSubject::new(&single_doc_id, None, None),
+        tenant: None,
+    };
+    handle_socket_upgrade(ws, Path(single_doc_id), access, State(server_state)).await
 }
 
 async fn handle_socket(
     socket: WebSocket,
     awareness: Arc<RwLock<Awareness>>,
     authorization: Authorization,
-    cancellation_token: CancellationToken,
+    drain_token: CancellationToken,
+    audit_log: AuditLog,
+    subject: AuditSubject,
 ) {
+    audit_log.record(&subject, AuditEventKind::ConnectionOpened { authorization });
+
     let (mut sink, mut stream) = socket.split();
     let (send, mut recv) = channel(1024);
 
@@ -628,7 +1803,11 @@ async fn handle_socket(
                     let Some(msg) = msg else {
                         break;
                     };
-                    let _ = sink.send(Message::Binary(msg)).await;
+                    let is_close = matches!(msg, Message::Close(_));
+                    let _ = sink.send(msg).await;
+                    if is_close {
+                        break;
+                    }
                 }
                 _ = ticker.tick() => {
                     if last_pong_clone.read().expect("Failed to get read lock on last_pong").elapsed() > PONG_TIMEOUT {
@@ -641,8 +1820,9 @@ async fn handle_socket(
         }
     });
 
+    let close_send = send.clone();
     let connection = DocConnection::new(awareness, authorization, move |bytes| {
-        if let Err(e) = send.try_send(bytes.to_vec()) {
+        if let Err(e) = send.try_send(Message::Binary(bytes.to_vec())) {
             tracing::warn!(?e, "Error sending message");
         }
     });
@@ -675,12 +1855,21 @@ async fn handle_socket(
                     tracing::warn!(?e, "Error handling message");
                 }
             }
-            _ = cancellation_token.cancelled() => {
-                tracing::debug!("Closing doc connection due to server cancel...");
+            _ = drain_token.cancelled() => {
+                tracing::debug!("Closing doc connection due to server drain...");
+                let close = Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server is shutting down.".into(),
+                }));
+                if let Err(e) = close_send.try_send(close) {
+                    tracing::warn!(?e, "Error sending close message");
+                }
                 break;
             }
         }
     }
+
+    audit_log.record(&subject, AuditEventKind::ConnectionClosed { authorization });
 }
 
 async fn check_store(
@@ -693,8 +1882,10 @@ async fn check_store(
         return Ok(Json(json!({"ok": false, "error": "No store set."})));
     };
 
-    // The check_store endpoint for the native server is kind of moot, since
-    // the server will not start if store is not ok.
+    if let Err(e) = server_state.check_store().await {
+        return Ok(Json(json!({"ok": false, "error": e.to_string()})));
+    }
+
     Ok(Json(json!({"ok": true})))
 }
 
@@ -709,31 +1900,102 @@ async fn check_store_deprecated(
 }
 
 /// Always returns a 200 OK response, as long as we are listening.
-async fn ready() -> Result<Json<Value>, AppError> {
+async fn live() -> Result<Json<Value>, AppError> {
     Ok(Json(json!({"ok": true})))
 }
 
+async fn drain(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Json<DrainReport>, AppError> {
+    server_state.check_auth(auth_header)?;
+    let report = server_state.drain().await;
+    // A drained server should not keep running, so it shuts down once the report is sent.
+    server_state.cancellation_token.cancel();
+    Ok(Json(report))
+}
+
+/// Returns a 200 OK response only if the store is reachable and recent
+/// persists have succeeded; otherwise returns a 503.
+async fn ready(State(server_state): State<Arc<Server>>) -> Response {
+    let readiness = server_state.readiness();
+    let status = if readiness.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness)).into_response()
+}
+
+#[derive(Deserialize)]
+struct NewDocParams {
+    #[serde(rename = "docId")]
+    doc_id: Option<String>,
+}
+
+/// Creates a doc. The body is either a JSON `DocCreationRequest`, or, with the
+/// `application/octet-stream` content type, a Yjs v1 update to initialize the doc with,
+/// in which case the doc ID is taken from the `docId` query parameter.
 async fn new_doc(
     auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
     State(server_state): State<Arc<Server>>,
-    Json(body): Json<DocCreationRequest>,
+    Query(params): Query<NewDocParams>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<Json<NewDocResponse>, AppError> {
-    server_state.check_auth(auth_header)?;
+    let tenant = server_state.check_tenant_auth(auth_header)?;
+    let tenant = tenant.as_deref();
+    server_state.check_not_draining()?;
+
+    let is_binary = headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/octet-stream");
+    let (doc_id, initial_state) = if is_binary {
+        Update::decode_v1(&body).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                anyhow!("Body is not a valid Yjs update: {}", e),
+            )
+        })?;
+        (params.doc_id, Some(body.to_vec()))
+    } else {
+        let body: DocCreationRequest =
+            serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        let initial_state = body
+            .initial_state()
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        (body.doc_id, initial_state)
+    };
 
-    let doc_id = if let Some(doc_id) = body.doc_id {
+    let doc_id = if let Some(initial_state) = initial_state {
+        let doc_id = doc_id.unwrap_or_else(|| nanoid::nanoid!());
         if !validate_doc_name(doc_id.as_str()) {
             Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document name")))?
         }
+        server_state
+            .create_doc_with_update(&doc_key(tenant, &doc_id)?, &initial_state)
+            .await
+            .map_err(doc_creation_error)?;
 
+        doc_id
+    } else if let Some(doc_id) = doc_id {
+        if !validate_doc_name(doc_id.as_str()) {
+            Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document name")))?
+        }
         server_state
-            .get_or_create_doc(doc_id.as_str())
+            .get_or_create_doc(&doc_key(tenant, &doc_id)?)
             .await
-            .map_err(|e| {
-                tracing::error!(?e, "Failed to create doc");
-                (StatusCode::INTERNAL_SERVER_ERROR, e)
-            })?;
+            .map_err(doc_creation_error)?;
 
         doc_id
+    } else if let Some(tenant) = tenant {
+        let doc_id = nanoid::nanoid!();
+        server_state
+            .load_doc(&doc_key(Some(tenant), &doc_id)?)
+            .await
+            .map_err(doc_creation_error)?;
+        doc_id
     } else {
         server_state.create_doc().await.map_err(|d| {
             tracing::error!(?d, "Failed to create doc");
@@ -744,6 +2006,19 @@ async fn new_doc(
     Ok(Json(NewDocResponse { doc_id }))
 }
 
+/// Maps an error of creating (or loading) a doc to a response: a conflict if the doc
+/// already exists, and forbidden if its tenant has reached its doc quota.
+fn doc_creation_error(e: anyhow::Error) -> AppError {
+    if e.is::<DocExists>() {
+        AppError(StatusCode::CONFLICT, e)
+    } else if e.is::<DocQuotaExceeded>() {
+        AppError(StatusCode::FORBIDDEN, e)
+    } else {
+        tracing::error!(?e, "Failed to create doc");
+        AppError(StatusCode::INTERNAL_SERVER_ERROR, e)
+    }
+}
+
 async fn auth_doc(
     auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
     TypedHeader(host): TypedHeader<headers::Host>,
@@ -751,15 +2026,31 @@ async fn auth_doc(
     Path(doc_id): Path<String>,
     body: Option<Json<AuthDocRequest>>,
 ) -> Result<Json<ClientToken>, AppError> {
-    server_state.check_auth(auth_header)?;
+    let tenant = server_state.check_tenant_auth(auth_header)?;
 
     let Json(AuthDocRequest {
         authorization,
+        user_id,
         valid_for_seconds,
-        ..
+        doc_pattern,
     }) = body.unwrap_or_default();
 
-    if !server_state.doc_exists(&doc_id).await {
+    if let Some(doc_pattern) = &doc_pattern {
+        if !validate_doc_pattern(doc_pattern) {
+            Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document pattern")))?;
+        }
+        if !doc_pattern_matches(doc_pattern, &doc_id) {
+            Err((
+                StatusCode::BAD_REQUEST,
+                anyhow!("Document pattern {} does not match {}", doc_pattern, doc_id),
+            ))?;
+        }
+    }
+
+    if !server_state
+        .doc_exists(&doc_key(tenant.as_deref(), &doc_id)?)
+        .await
+    {
         Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
     }
 
@@ -767,8 +2058,25 @@ async fn auth_doc(
     let expiration_time =
         ExpirationTimeEpochMillis(current_time_epoch_millis() + valid_for_seconds * 1000);
 
-    let token = if let Some(auth) = &server_state.authenticator {
-        let token = auth.gen_doc_token(&doc_id, authorization, expiration_time);
+    // Tokens for a tenant's docs are signed with the tenant's key, which binds them
+    // to the tenant.
+    let authenticator = match &tenant {
+        Some(tenant) => Some(tenant.authenticator()),
+        None => server_state.authenticator.as_ref(),
+    };
+    let token = if let Some(auth) = authenticator {
+        let token = if let Some(doc_pattern) = &doc_pattern {
+            auth.gen_doc_pattern_token(
+                doc_pattern,
+                authorization,
+                user_id.as_deref(),
+                expiration_time,
+            )
+        } else if let Some(user_id) = user_id.as_ref().filter(|_| server_state.doc_token_user_ids) {
+            auth.gen_doc_token_for_user(&doc_id, authorization, user_id, expiration_time)
+        } else {
+            auth.gen_doc_token(&doc_id, authorization, expiration_time)
+        };
         Some(token)
     } else {
         None
@@ -833,7 +2141,179 @@ fn get_authorization_from_plane_header(headers: HeaderMap) -> Result<Authorizati
 #[cfg(test)]
 mod test {
     use super::*;
-    use y_sweet_core::api_types::Authorization;
+    use async_trait::async_trait;
+    use y_sweet_core::{
+        api_types::Authorization,
+        store::{ExpectedVersion, Result as StoreResult, StoreError},
+        tenant::{TenantConfig, TenantQuotas},
+    };
+    use yrs::{updates::encoder::Encode, GetString, ReadTxn, Text, Transact};
+
+    #[derive(Default)]
+    struct MemoryStore {
+        data: Arc<DashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl Store for MemoryStore {
+        async fn init(&self) -> StoreResult<()> {
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>> {
+            Ok(self.data.get(key).map(|v| v.clone()))
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> StoreResult<()> {
+            self.data.insert(key.to_owned(), value);
+            Ok(())
+        }
+
+        async fn remove(&self, key: &str) -> StoreResult<()> {
+            self.data.remove(key);
+            Ok(())
+        }
+
+        async fn exists(&self, key: &str) -> StoreResult<bool> {
+            Ok(self.data.contains_key(key))
+        }
+
+        async fn list(&self, prefix: &str) -> StoreResult<Vec<String>> {
+            let mut keys: Vec<String> = self
+                .data
+                .iter()
+                .map(|entry| entry.key().clone())
+                .filter(|key| key.starts_with(prefix))
+                .collect();
+            keys.sort();
+            Ok(keys)
+        }
+    }
+
+    struct UnreachableStore;
+
+    #[async_trait]
+    impl Store for UnreachableStore {
+        async fn init(&self) -> StoreResult<()> {
+            Ok(())
+        }
+
+        async fn get(&self, _key: &str) -> StoreResult<Option<Vec<u8>>> {
+            Err(StoreError::ConnectionError("unreachable".to_string()))
+        }
+
+        async fn set(&self, _key: &str, _value: Vec<u8>) -> StoreResult<()> {
+            Err(StoreError::ConnectionError("unreachable".to_string()))
+        }
+
+        async fn remove(&self, _key: &str) -> StoreResult<()> {
+            Err(StoreError::ConnectionError("unreachable".to_string()))
+        }
+
+        async fn exists(&self, _key: &str) -> StoreResult<bool> {
+            Err(StoreError::ConnectionError("unreachable".to_string()))
+        }
+    }
+
+    /// Never finds an object, as if every read happened just before another server
+    /// wrote it. Writes go to the wrapped store.
+    struct StaleReadStore<S>(S);
+
+    #[async_trait]
+    impl<S: Store + Send + Sync> Store for StaleReadStore<S> {
+        async fn init(&self) -> StoreResult<()> {
+            self.0.init().await
+        }
+
+        async fn get(&self, _key: &str) -> StoreResult<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> StoreResult<()> {
+            self.0.set(key, value).await
+        }
+
+        async fn set_if(
+            &self,
+            key: &str,
+            value: Vec<u8>,
+            expected: &ExpectedVersion,
+        ) -> StoreResult<Option<String>> {
+            self.0.set_if(key, value, expected).await
+        }
+
+        async fn remove(&self, key: &str) -> StoreResult<()> {
+            self.0.remove(key).await
+        }
+
+        async fn exists(&self, _key: &str) -> StoreResult<bool> {
+            Ok(false)
+        }
+    }
+
+    async fn server(store: Option<Box<dyn Store>>, authenticator: Option<Authenticator>) -> Server {
+        Server::new(
+            store,
+            Duration::from_secs(60),
+            authenticator,
+            None,
+            CancellationToken::new(),
+            true,
+            None,
+            false,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn server_with_store(store: Box<dyn Store>) -> Server {
+        server(Some(store), None).await
+    }
+
+    async fn server_with_authenticator(authenticator: Authenticator) -> Server {
+        server(None, Some(authenticator)).await
+    }
+
+    #[tokio::test]
+    async fn test_auth_doc_user_ids() {
+        let authenticator = Authenticator::gen_key().unwrap();
+        let server_token = authenticator.server_token();
+        for doc_token_user_ids in [false, true] {
+            let server_state = server_with_authenticator(
+                Authenticator::new(&authenticator.private_key()).unwrap(),
+            )
+            .await
+            .with_doc_token_user_ids(doc_token_user_ids);
+            let server_state = Arc::new(server_state);
+            let doc_id = server_state.create_doc().await.unwrap();
+
+            let Json(token) = auth_doc(
+                Some(TypedHeader(
+                    headers::Authorization::bearer(&server_token).unwrap(),
+                )),
+                TypedHeader(headers::Host::from(http::uri::Authority::from_static(
+                    "localhost",
+                ))),
+                State(server_state.clone()),
+                Path(doc_id.clone()),
+                Some(Json(AuthDocRequest {
+                    authorization: Authorization::Full,
+                    user_id: Some("user1".to_string()),
+                    valid_for_seconds: None,
+                    doc_pattern: None,
+                })),
+            )
+            .await
+            .unwrap();
+
+            // Unless enabled, tokens stay decodable by servers that predate user IDs.
+            let access = server_state
+                .verify_doc_token(token.token.as_deref(), &doc_id)
+                .unwrap();
+            let expected_user_id = doc_token_user_ids.then(|| "user1".to_string());
+            assert_eq!(access.subject.user_id, expected_user_id);
+        }
+    }
 
     #[tokio::test]
     async fn test_auth_doc() {
@@ -863,6 +2343,7 @@ mod test {
                 authorization: Authorization::Full,
                 user_id: None,
                 valid_for_seconds: None,
+                doc_pattern: None,
             })),
         )
         .await
@@ -874,6 +2355,886 @@ mod test {
         assert!(token.token.is_none());
     }
 
+    #[tokio::test]
+    async fn test_doc_stats() {
+        let server_state = Arc::new(server(None, None).await);
+
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+        server_state
+            .get_or_create_doc(&doc_id)
+            .await
+            .unwrap()
+            .apply_update(&update)
+            .unwrap();
+
+        let Json(stats) = get_doc_stats(State(server_state.clone()), Path(doc_id.clone()), None)
+            .await
+            .unwrap();
+
+        assert!(stats.encoded_size > 0);
+        assert!(stats.sync_kv_entries > 0);
+        assert_eq!(stats.pending_updates, 0);
+        assert!(stats.dirty);
+        assert_eq!(stats.connections, 0);
+        assert_eq!(stats.awareness_clients, 0);
+        assert_eq!(stats.state_vector_clients, 1);
+
+        // Only open connections count, not other references to the doc.
+        {
+            let dwskv = server_state.get_or_create_doc(&doc_id).await.unwrap();
+            let _awareness = dwskv.awareness();
+            let connection = dwskv.open_connection();
+            assert_eq!(dwskv.stats().connections, 1);
+            drop(connection);
+            assert_eq!(dwskv.stats().connections, 0);
+        }
+
+        assert!(
+            get_doc_stats(State(server_state), Path("missing".to_string()), None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compact_doc() {
+        let server_state = Arc::new(server_with_store(Box::new(MemoryStore::default())).await);
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+        server_state
+            .get_or_create_doc(&doc_id)
+            .await
+            .unwrap()
+            .apply_update(&update)
+            .unwrap();
+
+        let Json(report) = compact_doc(
+            State(server_state.clone()),
+            Path(doc_id.clone()),
+            None,
+            Some(Json(CompactDocRequest { dry_run: true })),
+        )
+        .await
+        .unwrap();
+        assert!(report.dry_run);
+        assert!(report.size_after <= report.size_before);
+
+        let Json(report) = compact_doc(
+            State(server_state.clone()),
+            Path(doc_id.clone()),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!report.dry_run);
+        let dwskv = server_state.get_or_create_doc(&doc_id).await.unwrap();
+        assert!(!dwskv.sync_kv().is_dirty());
+        assert_eq!(dwskv.sync_kv().pending_update_count(), 0);
+        drop(dwskv);
+
+        assert!(
+            compact_doc(State(server_state), Path("missing".to_string()), None, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fork_doc() {
+        let mut server_state = server_with_store(Box::<MemoryStore>::default()).await;
+        server_state.skip_gc = true;
+        let server_state = Arc::new(server_state);
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        let text = |doc_id: &str| {
+            let server_state = server_state.clone();
+            let doc_id = doc_id.to_owned();
+            async move {
+                let dwskv = server_state.get_or_create_doc(&doc_id).await.unwrap();
+                let awareness = dwskv.awareness();
+                let awareness = awareness.read().unwrap();
+                let text = awareness.doc.get_or_insert_text("text");
+                let txn = awareness.doc.transact();
+                text.get_string(&txn)
+            }
+        };
+        let insert = |doc_id: &str, index: u32, chunk: &str| {
+            let dwskv = server_state.docs.get(doc_id).unwrap();
+            let awareness = dwskv.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc.get_or_insert_text("text");
+            text.insert(&mut awareness.doc.transact_mut(), index, chunk);
+            let snapshot = awareness.doc.transact().snapshot();
+            snapshot
+        };
+
+        let snapshot = insert(&doc_id, 0, "hello");
+        insert(&doc_id, 5, " world");
+
+        let Json(NewDocResponse { doc_id: fork_id }) = fork_doc(
+            State(server_state.clone()),
+            Path(doc_id.clone()),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_ne!(fork_id, doc_id);
+        assert_eq!(text(&fork_id).await, "hello world");
+
+        // The fork is independent of its source.
+        insert(&fork_id, 0, "fork: ");
+        assert_eq!(text(&doc_id).await, "hello world");
+
+        let request = DocForkRequest {
+            doc_id: Some("at-snapshot".to_string()),
+            snapshot: Some(BASE64.encode(&snapshot.encode_v1())),
+        };
+        let Json(NewDocResponse {
+            doc_id: snapshot_fork_id,
+        }) = fork_doc(
+            State(server_state.clone()),
+            Path(doc_id.clone()),
+            None,
+            Some(Json(request)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(snapshot_fork_id, "at-snapshot");
+        assert_eq!(text("at-snapshot").await, "hello");
+
+        let request = DocForkRequest {
+            doc_id: Some(fork_id),
+            snapshot: None,
+        };
+        let err = fork_doc(
+            State(server_state.clone()),
+            Path(doc_id),
+            None,
+            Some(Json(request)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::CONFLICT);
+
+        let err = fork_doc(State(server_state), Path("missing".to_string()), None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_new_doc_with_initial_state() {
+        let server_state = Arc::new(server_with_store(Box::new(MemoryStore::default())).await);
+        let new_doc = |doc_id: Option<&str>, headers: HeaderMap, body: Vec<u8>| {
+            let params = NewDocParams {
+                doc_id: doc_id.map(str::to_owned),
+            };
+            new_doc(
+                None,
+                State(server_state.clone()),
+                Query(params),
+                headers,
+                Bytes::from(body),
+            )
+        };
+        let text = |doc_id: String| {
+            let server_state = server_state.clone();
+            async move {
+                let update = server_state
+                    .get_or_create_doc(&doc_id)
+                    .await
+                    .unwrap()
+                    .as_update();
+                let doc = yrs::Doc::new();
+                let text = doc.get_or_insert_text("text");
+                let mut txn = doc.transact_mut();
+                txn.apply_update(Update::decode_v1(&update).unwrap());
+                text.get_string(&txn)
+            }
+        };
+
+        let body = json!({"docId": "json", "initialContent": {"text": "hello"}});
+        let Json(response) = new_doc(None, HeaderMap::new(), body.to_string().into_bytes())
+            .await
+            .unwrap();
+        assert_eq!(response.doc_id, "json");
+        assert_eq!(text(response.doc_id).await, "hello");
+
+        let err = new_doc(None, HeaderMap::new(), body.to_string().into_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::CONFLICT);
+
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "binary");
+            txn.encode_update_v1()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+        let Json(response) = new_doc(Some("binary"), headers.clone(), update)
+            .await
+            .unwrap();
+        assert_eq!(text(response.doc_id).await, "binary");
+
+        let err = new_doc(None, headers, b"not an update".to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        let body = json!({"initialContent": "not an object"});
+        let err = new_doc(None, HeaderMap::new(), body.to_string().into_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        // Without initial content, creating an existing doc is still a no-op.
+        let body = json!({"docId": "json"});
+        let Json(response) = new_doc(None, HeaderMap::new(), body.to_string().into_bytes())
+            .await
+            .unwrap();
+        assert_eq!(text(response.doc_id).await, "hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_create() {
+        let update = |content: &str| {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, content);
+            txn.encode_update_v1()
+        };
+        let text = |server: Arc<Server>| async move {
+            let update = server.get_or_create_doc("doc").await.unwrap().as_update();
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            txn.apply_update(Update::decode_v1(&update).unwrap());
+            text.get_string(&txn)
+        };
+
+        let server = Arc::new(server_with_store(Box::<MemoryStore>::default()).await);
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let server = server.clone();
+                let update = update(&i.to_string());
+                tokio::spawn(async move { server.create_doc_with_update("doc", &update).await })
+            })
+            .collect();
+        let mut created = Vec::new();
+        for (i, handle) in handles.into_iter().enumerate() {
+            match handle.await.unwrap() {
+                Ok(()) => created.push(i.to_string()),
+                Err(e) => assert!(e.is::<DocExists>(), "{:?}", e),
+            }
+        }
+        // Exactly one create wins, and the loaded doc is the one it created.
+        assert_eq!(created.len(), 1);
+        assert_eq!(text(server.clone()).await, created[0]);
+
+        // A server that has not loaded the doc learns from the store that it exists.
+        let dir = std::env::temp_dir().join(format!("y-sweet-server-{}", nanoid::nanoid!()));
+        let server_with_dir = || async {
+            Arc::new(
+                server_with_store(Box::new(
+                    crate::stores::filesystem::FileSystemStore::new(dir.clone()).unwrap(),
+                ))
+                .await,
+            )
+        };
+        let (a, b) = (server_with_dir().await, server_with_dir().await);
+        a.create_doc_with_update("doc", &update("a")).await.unwrap();
+        assert!(b
+            .create_doc_with_update("doc", &update("b"))
+            .await
+            .unwrap_err()
+            .is::<DocExists>());
+        assert_eq!(text(b).await, "a");
+
+        // A server whose read raced with the other server's create only finds out from
+        // the conditional write.
+        let racing = server_with_store(Box::new(StaleReadStore(
+            crate::stores::filesystem::FileSystemStore::new(dir.clone()).unwrap(),
+        )))
+        .await;
+        assert!(racing
+            .create_doc_with_update("doc", &update("c"))
+            .await
+            .unwrap_err()
+            .is::<DocExists>());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // A store without conditional writes cannot tell, so the later create wins.
+        let store = MemoryStore::default();
+        let data = store.data.clone();
+        let a = server_with_store(Box::new(store)).await;
+        a.create_doc_with_update("doc", &update("a")).await.unwrap();
+        let snapshot = data.get("doc/data.ysweet").unwrap().clone();
+        let racing =
+            server_with_store(Box::new(StaleReadStore(MemoryStore { data: data.clone() }))).await;
+        racing
+            .create_doc_with_update("doc", &update("c"))
+            .await
+            .unwrap();
+        assert_ne!(*data.get("doc/data.ysweet").unwrap(), snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_list_docs_pages() {
+        let server_state = Arc::new(server_with_store(Box::<MemoryStore>::default()).await);
+        let mut expected = Vec::new();
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+        for doc_id in ["a", "a-b", "b", "c_d", "d"] {
+            server_state
+                .create_doc_with_update(doc_id, &update)
+                .await
+                .unwrap();
+            expected.push(doc_id.to_string());
+        }
+        // Loaded docs that have not been persisted yet are listed too.
+        for doc_id in ["aa", "e"] {
+            server_state.load_doc(doc_id).await.unwrap();
+            expected.push(doc_id.to_string());
+        }
+        expected.sort_by_key(|doc_id| format!("{}{}", doc_id, DOC_SNAPSHOT_SUFFIX));
+
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let Json(page) = list_docs(
+                State(server_state.clone()),
+                Query(ListDocsParams {
+                    cursor: cursor.clone(),
+                    limit: Some(2),
+                    ..Default::default()
+                }),
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(page.docs.len() <= 2);
+            listed.extend(page.docs.into_iter().map(|doc| doc.doc_id));
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => break,
+            }
+        }
+        assert_eq!(listed, expected);
+
+        for limit in [0, MAX_LIST_DOCS_LIMIT + 1] {
+            let err = list_docs(
+                State(server_state.clone()),
+                Query(ListDocsParams {
+                    limit: Some(limit),
+                    ..Default::default()
+                }),
+                None,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_doc_metadata() {
+        let server_state = Arc::new(server_with_store(Box::<MemoryStore>::default()).await);
+        let a = server_state.create_doc().await.unwrap();
+        let b = server_state.create_doc().await.unwrap();
+
+        let Json(metadata) = get_doc_metadata(State(server_state.clone()), Path(a.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(metadata, DocMetadata::default());
+
+        let metadata: DocMetadata = serde_json::from_value(json!({
+            "owner": "user-1",
+            "title": "Notes",
+            "createdAt": 1700000000000u64,
+            "tags": ["draft"],
+            "color": "blue",
+        }))
+        .unwrap();
+        assert_eq!(metadata.extra.get("color"), Some(&json!("blue")));
+        let Json(stored) = put_doc_metadata(
+            State(server_state.clone()),
+            Path(a.clone()),
+            None,
+            Json(metadata.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stored, metadata);
+        let Json(stored) = get_doc_metadata(State(server_state.clone()), Path(a.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(stored, metadata);
+
+        let err = put_doc_metadata(
+            State(server_state.clone()),
+            Path("missing".to_string()),
+            None,
+            Json(metadata.clone()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+
+        let Json(listing) = list_docs(
+            State(server_state.clone()),
+            Query(ListDocsParams::default()),
+            None,
+        )
+        .await
+        .unwrap();
+        let mut expected = vec![
+            DocListing {
+                doc_id: a.clone(),
+                metadata: Some(metadata.clone()),
+            },
+            DocListing {
+                doc_id: b.clone(),
+                metadata: None,
+            },
+        ];
+        expected.sort_by_key(|doc| format!("{}{}", doc.doc_id, DOC_SNAPSHOT_SUFFIX));
+        assert_eq!(listing.docs, expected);
+        assert_eq!(listing.next_cursor, None);
+
+        let Json(listing) = list_docs(
+            State(server_state.clone()),
+            Query(ListDocsParams {
+                tag: Some("draft".to_string()),
+                ..Default::default()
+            }),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(listing.docs.len(), 1);
+        assert_eq!(listing.docs[0].doc_id, a);
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let tenant = |key_id: &str, quotas: TenantQuotas| TenantConfig {
+            key_id: key_id.to_string(),
+            private_key: Authenticator::gen_key().unwrap().private_key(),
+            store_prefix: None,
+            quotas,
+        };
+        let quotas = TenantQuotas {
+            max_docs: Some(1),
+            max_connections: Some(1),
+        };
+        let tenants = Tenants::new(vec![
+            tenant("acme", quotas),
+            tenant("globex", Default::default()),
+        ])
+        .unwrap();
+        let acme_token = tenants.get("acme").unwrap().authenticator().server_token();
+        let globex_token = tenants
+            .get("globex")
+            .unwrap()
+            .authenticator()
+            .server_token();
+
+        let store = MemoryStore::default();
+        let data = store.data.clone();
+        let server_state = server(
+            Some(Box::new(store)),
+            Some(Authenticator::gen_key().unwrap()),
+        )
+        .await
+        .with_tenants(tenants);
+        let server_state = Arc::new(server_state);
+        let bearer =
+            |token: &str| Some(TypedHeader(headers::Authorization::bearer(token).unwrap()));
+
+        // Both tenants can create a doc with the same ID, and each gets its own.
+        for token in [&acme_token, &globex_token] {
+            let body = json!({"docId": "shared", "initialContent": {"text": "hello"}});
+            let _ = new_doc(
+                bearer(token),
+                State(server_state.clone()),
+                Query(NewDocParams { doc_id: None }),
+                HeaderMap::new(),
+                Bytes::from(body.to_string()),
+            )
+            .await
+            .unwrap();
+        }
+        assert!(data.contains_key("acme/shared/data.ysweet"));
+        assert!(data.contains_key("globex/shared/data.ysweet"));
+
+        // acme is at its doc quota.
+        let err = new_doc(
+            bearer(&acme_token),
+            State(server_state.clone()),
+            Query(NewDocParams { doc_id: None }),
+            HeaderMap::new(),
+            Bytes::from("{}"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+        // That includes docs that are created by being loaded, as by an update.
+        let err = server_state.load_doc("acme/other").await.unwrap_err();
+        assert!(err.is::<DocQuotaExceeded>());
+        server_state.get_or_create_doc("acme/shared").await.unwrap();
+
+        // A doc token issued by a tenant only opens that tenant's doc.
+        let Json(client_token) = auth_doc(
+            bearer(&acme_token),
+            TypedHeader(headers::Host::from(http::uri::Authority::from_static(
+                "localhost",
+            ))),
+            State(server_state.clone()),
+            Path("shared".to_string()),
+            None,
+        )
+        .await
+        .unwrap();
+        let doc_token = client_token.token.unwrap();
+        assert!(doc_token.starts_with("acme."));
+        let access = server_state
+            .verify_doc_token(Some(&doc_token), "shared")
+            .unwrap();
+        assert_eq!(access.tenant.unwrap().id(), "acme");
+
+        // The server's own token does not see tenants' docs.
+        let root_token = server_state.authenticator.as_ref().unwrap().server_token();
+        let Json(listing) = list_docs(
+            State(server_state.clone()),
+            Query(ListDocsParams::default()),
+            bearer(&root_token),
+        )
+        .await
+        .unwrap();
+        assert!(listing.docs.is_empty());
+        let Json(listing) = list_docs(
+            State(server_state.clone()),
+            Query(ListDocsParams::default()),
+            bearer(&globex_token),
+        )
+        .await
+        .unwrap();
+        assert_eq!(listing.docs.len(), 1);
+        assert_eq!(listing.docs[0].doc_id, "shared");
+
+        // A tenant's server token is not accepted for server-wide endpoints.
+        let err = drain(bearer(&acme_token), State(server_state.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+
+        let acme = server_state.tenants.as_ref().unwrap().get("acme").unwrap();
+        let connection = server_state.open_tenant_connection(acme).unwrap();
+        let err = server_state.open_tenant_connection(acme).unwrap_err();
+        assert_eq!(err.0, StatusCode::TOO_MANY_REQUESTS);
+        drop(connection);
+        server_state.open_tenant_connection(acme).unwrap();
+    }
+
+    struct ReaderAuthorizer;
+
+    #[async_trait]
+    impl Authorizer for ReaderAuthorizer {
+        async fn authorize(
+            &self,
+            _doc_id: &str,
+            token: Option<&str>,
+        ) -> Result<Authorization, AuthorizerError> {
+            match token {
+                Some("reader") => Ok(Authorization::ReadOnly),
+                _ => Err(AuthorizerError::Denied("Unknown token.".to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_doc_id_cannot_escape_tenant() {
+        let tenant = |key_id: &str| TenantConfig {
+            key_id: key_id.to_string(),
+            private_key: Authenticator::gen_key().unwrap().private_key(),
+            store_prefix: None,
+            quotas: TenantQuotas::default(),
+        };
+        let tenants = Tenants::new(vec![tenant("acme"), tenant("globex")]).unwrap();
+        let acme_token = tenants.get("acme").unwrap().authenticator().server_token();
+
+        let dir = std::env::temp_dir().join(format!("y-sweet-server-{}", nanoid::nanoid!()));
+        let store = crate::stores::filesystem::FileSystemStore::new(dir.clone()).unwrap();
+        assert!(store.get("acme/../globex/doc1/data.ysweet").await.is_err());
+        let server_state = Arc::new(
+            server(
+                Some(Box::new(store)),
+                Some(Authenticator::gen_key().unwrap()),
+            )
+            .await
+            .with_tenants(tenants),
+        );
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "secret");
+            txn.encode_update_v1()
+        };
+        server_state
+            .create_doc_with_update("globex/doc1", &update)
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = server_state.routes();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // axum decodes the path, so the doc ID is `../globex/doc1`.
+        for path in ["as-update", "stats", "metadata"] {
+            let response = reqwest::Client::new()
+                .get(format!("http://{}/d/..%2Fglobex%2Fdoc1/{}", addr, path))
+                .bearer_auth(&acme_token)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        assert!(!server_state.docs.contains_key("acme/../globex/doc1"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_creates_within_doc_quota() {
+        let tenants = Tenants::new(vec![TenantConfig {
+            key_id: "acme".to_string(),
+            private_key: Authenticator::gen_key().unwrap().private_key(),
+            store_prefix: None,
+            quotas: TenantQuotas {
+                max_docs: Some(3),
+                max_connections: None,
+            },
+        }])
+        .unwrap();
+        let server_state = Arc::new(
+            server_with_store(Box::<MemoryStore>::default())
+                .await
+                .with_tenants(tenants),
+        );
+
+        let handles: Vec<_> = (0..10)
+            .map(|i| {
+                let server_state = server_state.clone();
+                tokio::spawn(async move { server_state.load_doc(&format!("acme/doc{}", i)).await })
+            })
+            .collect();
+        let mut created = 0;
+        for handle in handles {
+            match handle.await.unwrap() {
+                Ok(()) => created += 1,
+                Err(e) => assert!(e.is::<DocQuotaExceeded>(), "{:?}", e),
+            }
+        }
+        assert_eq!(created, 3);
+    }
+
+    #[tokio::test]
+    async fn test_authorizer() {
+        let authenticator = Authenticator::gen_key().unwrap();
+        let server_token = authenticator.server_token();
+        let server_state = server_with_authenticator(authenticator)
+            .await
+            .with_authorizer(Arc::new(ReaderAuthorizer));
+        let server_state = Arc::new(server_state);
+        let doc_id = server_state.create_doc().await.unwrap();
+        let bearer =
+            |token: &str| Some(TypedHeader(headers::Authorization::bearer(token).unwrap()));
+
+        get_doc_as_update(
+            State(server_state.clone()),
+            Path(doc_id.clone()),
+            bearer("reader"),
+        )
+        .await
+        .unwrap();
+        let err = update_doc(
+            Path(doc_id.clone()),
+            State(server_state.clone()),
+            bearer("reader"),
+            Bytes::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+
+        // Doc tokens are no longer verified by the server itself.
+        let doc_token = server_state.authenticator.as_ref().unwrap().gen_doc_token(
+            &doc_id,
+            Authorization::Full,
+            ExpirationTimeEpochMillis::max(),
+        );
+        let err = get_doc_as_update(
+            State(server_state.clone()),
+            Path(doc_id.clone()),
+            bearer(&doc_token),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+
+        // Server tokens still give full access.
+        let access = server_state
+            .authorize_doc(Some(&server_token), &doc_id)
+            .await
+            .unwrap();
+        assert!(access.authorization == Authorization::Full);
+
+        // Without an authenticator, there is no server token, so server endpoints are
+        // closed rather than open to everyone.
+        let server_state = server(None, None)
+            .await
+            .with_authorizer(Arc::new(ReaderAuthorizer));
+        let err = server_state.check_auth(None).unwrap_err();
+        assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_auth_doc_with_pattern() {
+        let authenticator = Authenticator::gen_key().unwrap();
+        let server_token = authenticator.server_token();
+        let server_state = Arc::new(server_with_authenticator(authenticator).await);
+        server_state.load_doc("ws123-a").await.unwrap();
+
+        let request_token = |doc_pattern: &str| {
+            auth_doc(
+                Some(TypedHeader(
+                    headers::Authorization::bearer(&server_token).unwrap(),
+                )),
+                TypedHeader(headers::Host::from(http::uri::Authority::from_static(
+                    "localhost",
+                ))),
+                State(server_state.clone()),
+                Path("ws123-a".to_string()),
+                Some(Json(AuthDocRequest {
+                    authorization: Authorization::ReadOnly,
+                    user_id: None,
+                    valid_for_seconds: None,
+                    doc_pattern: Some(doc_pattern.to_string()),
+                })),
+            )
+        };
+
+        let Json(token) = request_token("ws123-*").await.unwrap();
+        let token = token.token.unwrap();
+        for doc in ["ws123-a", "ws123-b"] {
+            let access = server_state.verify_doc_token(Some(&token), doc).unwrap();
+            assert!(access.authorization == Authorization::ReadOnly);
+        }
+        assert!(server_state
+            .verify_doc_token(Some(&token), "ws124-a")
+            .is_err());
+
+        // The pattern must be valid and cover the requested doc.
+        for doc_pattern in ["ws124-*", "ws123/*", ""] {
+            let Err(err) = request_token(doc_pattern).await else {
+                panic!("Expected {doc_pattern:?} to be rejected.");
+            };
+            assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let server_state = server_with_store(Box::<MemoryStore>::default()).await;
+        server_state.update_store_health().await;
+        let readiness = server_state.readiness();
+        assert!(readiness.ok);
+        assert_eq!(readiness.status, ReadinessStatus::Ok);
+
+        server_state.failed_persists.insert("doc123".to_string());
+        let readiness = server_state.readiness();
+        assert!(!readiness.ok);
+        assert_eq!(readiness.status, ReadinessStatus::Degraded);
+        assert_eq!(readiness.failed_persists, 1);
+
+        let server_state = server_with_store(Box::new(UnreachableStore)).await;
+        assert!(server_state.readiness().ok);
+        server_state.update_store_health().await;
+        let readiness = server_state.readiness();
+        assert!(!readiness.ok);
+        assert_eq!(readiness.status, ReadinessStatus::Unavailable);
+        // The store's own error is logged, but not served to unauthenticated callers.
+        assert_eq!(readiness.error.as_deref(), Some("Store is unavailable."));
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let server_state = server_with_store(Box::<MemoryStore>::default()).await;
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+        server_state
+            .get_or_create_doc(&doc_id)
+            .await
+            .unwrap()
+            .apply_update(&update)
+            .unwrap();
+        assert!(server_state.docs.get(&doc_id).unwrap().sync_kv().is_dirty());
+
+        let report = server_state.drain().await;
+        assert_eq!(report.persisted, vec![doc_id.clone()]);
+        assert!(report.failed.is_empty());
+        assert!(!server_state.docs.get(&doc_id).unwrap().sync_kv().is_dirty());
+
+        let readiness = server_state.readiness();
+        assert!(!readiness.ok);
+        assert_eq!(readiness.status, ReadinessStatus::Draining);
+
+        // Changes are refused, since they might not be persisted before the server exits.
+        let err = update_doc(
+            Path(doc_id.clone()),
+            State(Arc::new(server_state)),
+            None,
+            Bytes::from(update),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
     #[tokio::test]
     async fn test_auth_doc_with_prefix() {
         let prefix: Url = "https://foo.bar".parse().unwrap();
@@ -0,0 +1,450 @@
+use async_trait::async_trait;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{create_dir_all, read_dir, remove_file},
+    path::PathBuf,
+    sync::Mutex,
+};
+use y_sweet_core::store::{ExpectedVersion, Result, Store, PREVIOUS_VERSION_SUFFIX};
+
+const CACHE_FILE_EXTENSION: &str = "cache";
+
+/// Keeps recently used objects of another store in a size-bounded LRU cache, so that
+/// reopening a doc shortly after it was unloaded does not fetch it from the store again.
+///
+/// Writes go to the inner store first and are cached once they succeed. Other servers
+/// may write to the same store, so a cached value is only served after checking with
+/// the inner store that its version is still current, which for S3 is a HEAD request
+/// instead of a download. Values without a version cannot be checked and are not
+/// cached.
+///
+/// With encryption, the cache belongs between the store and the `EncryptedStore`, so
+/// that it holds encrypted objects; the cache directory is not otherwise protected.
+pub struct CachedStore {
+    inner: Box<dyn Store>,
+    /// Directory that cached values are written to, or `None` to keep them in memory.
+    dir: Option<PathBuf>,
+    max_bytes: u64,
+    cache: Mutex<Cache>,
+}
+
+struct Cache {
+    entries: LruCache<String, CachedObject>,
+    bytes: u64,
+}
+
+struct CachedObject {
+    size: u64,
+    /// The version the store reported for the value.
+    version: String,
+    /// The value, if cached in memory.
+    value: Option<Vec<u8>>,
+}
+
+impl CachedStore {
+    /// Caches up to `max_bytes` of values in memory.
+    pub fn in_memory(inner: Box<dyn Store>, max_bytes: u64) -> Self {
+        Self::new(inner, None, max_bytes)
+    }
+
+    /// Caches up to `max_bytes` of values as files in `dir`. The index of the cache is
+    /// kept in memory, so files left over from a previous run are removed.
+    pub fn on_disk(
+        inner: Box<dyn Store>,
+        dir: PathBuf,
+        max_bytes: u64,
+    ) -> std::result::Result<Self, std::io::Error> {
+        create_dir_all(&dir)?;
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == CACHE_FILE_EXTENSION)
+            {
+                remove_file(path)?;
+            }
+        }
+        Ok(Self::new(inner, Some(dir), max_bytes))
+    }
+
+    fn new(inner: Box<dyn Store>, dir: Option<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            inner,
+            dir,
+            max_bytes,
+            cache: Mutex::new(Cache {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    fn file_path(&self, key: &str) -> Option<PathBuf> {
+        let name = format!("{:x}.{}", Sha256::digest(key), CACHE_FILE_EXTENSION);
+        self.dir.as_ref().map(|dir| dir.join(name))
+    }
+
+    /// Previous versions are only read to recover from a corrupt snapshot, and are
+    /// replaced by the inner store without going through us, so they are not cached.
+    fn is_cacheable(key: &str) -> bool {
+        !key.ends_with(PREVIOUS_VERSION_SUFFIX)
+    }
+
+    /// Returns the cached value and version of `key`, if it is still the current
+    /// version in the inner store. A stale entry is dropped.
+    async fn lookup(&self, key: &str) -> Result<Option<(Vec<u8>, String)>> {
+        let Some((value, version)) = self.lookup_cached(key) else {
+            return Ok(None);
+        };
+        if self.inner.get_version(key).await?.flatten().as_ref() != Some(&version) {
+            self.invalidate(key);
+            return Ok(None);
+        }
+        Ok(Some((value, version)))
+    }
+
+    /// Returns the cached value and version of `key`, without checking that they are
+    /// still current.
+    fn lookup_cached(&self, key: &str) -> Option<(Vec<u8>, String)> {
+        let mut cache = self.cache.lock().unwrap();
+        let entry = cache.entries.get(key)?;
+        let version = entry.version.clone();
+
+        let value = match (&entry.value, self.file_path(key)) {
+            (Some(value), _) => value.clone(),
+            (None, Some(path)) => match std::fs::read(path) {
+                Ok(value) => value,
+                Err(e) => {
+                    tracing::warn!(?e, key, "Failed to read cached object");
+                    self.evict(&mut cache, key);
+                    return None;
+                }
+            },
+            (None, None) => return None,
+        };
+        Some((value, version))
+    }
+
+    /// Caches `value` under `key`, if the store reported a version for it.
+    fn insert(&self, key: &str, value: Vec<u8>, version: Option<String>) {
+        let mut cache = self.cache.lock().unwrap();
+        self.evict(&mut cache, key);
+
+        let size = value.len() as u64;
+        let Some(version) = version else {
+            return;
+        };
+        if !Self::is_cacheable(key) || size > self.max_bytes {
+            return;
+        }
+
+        let value = match self.file_path(key) {
+            Some(path) => {
+                if let Err(e) = std::fs::write(path, &value) {
+                    tracing::warn!(?e, key, "Failed to write cached object");
+                    return;
+                }
+                None
+            }
+            None => Some(value),
+        };
+
+        cache.entries.put(
+            key.to_owned(),
+            CachedObject {
+                size,
+                version,
+                value,
+            },
+        );
+        cache.bytes += size;
+
+        while cache.bytes > self.max_bytes {
+            let Some((key, entry)) = cache.entries.pop_lru() else {
+                break;
+            };
+            cache.bytes -= entry.size;
+            self.remove_file(&key);
+        }
+    }
+
+    fn invalidate(&self, key: &str) {
+        let mut cache = self.cache.lock().unwrap();
+        self.evict(&mut cache, key);
+    }
+
+    fn evict(&self, cache: &mut Cache, key: &str) {
+        if let Some(entry) = cache.entries.pop(key) {
+            cache.bytes -= entry.size;
+            self.remove_file(key);
+        }
+    }
+
+    fn remove_file(&self, key: &str) {
+        if let Some(path) = self.file_path(key) {
+            if let Err(e) = remove_file(path) {
+                tracing::warn!(?e, key, "Failed to remove cached object");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Store for CachedStore {
+    async fn init(&self) -> Result<()> {
+        self.inner.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key).await?.map(|(value, _)| value))
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        // A plain `set` does not report the new version, so the value is not cached.
+        let result = self.inner.set(key, value).await;
+        self.invalidate(key);
+        result
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.invalidate(key);
+        self.inner.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        if let Some((value, version)) = self.lookup(key).await? {
+            return Ok(Some((value, Some(version))));
+        }
+        let result = self.inner.get_versioned(key).await?;
+        if let Some((value, version)) = &result {
+            self.insert(key, value.clone(), version.clone());
+        }
+        Ok(result)
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        self.inner.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        match self.inner.set_if(key, value.clone(), expected).await {
+            Ok(version) => {
+                self.insert(key, value, version.clone());
+                Ok(version)
+            }
+            Err(e) => {
+                // On a conflict our entry is stale, and on other errors we cannot tell
+                // whether the write happened.
+                self.invalidate(key);
+                Err(e)
+            }
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.inner.list_page(prefix, start_after, limit).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dashmap::DashMap;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use y_sweet_core::store::{
+        encrypted::{EncryptedStore, EncryptionKeys},
+        StoreError,
+    };
+
+    #[derive(Default, Clone)]
+    struct CountingStore {
+        data: Arc<DashMap<String, (Vec<u8>, u64)>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl CountingStore {
+        fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl Store for CountingStore {
+        async fn init(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.get_versioned(key).await?.map(|(value, _)| value))
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+            let version = self.data.get(key).map_or(0, |entry| entry.1) + 1;
+            self.data.insert(key.to_owned(), (value, version));
+            Ok(())
+        }
+
+        async fn remove(&self, key: &str) -> Result<()> {
+            self.data.remove(key);
+            Ok(())
+        }
+
+        async fn exists(&self, key: &str) -> Result<bool> {
+            Ok(self.data.contains_key(key))
+        }
+
+        async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(self
+                .data
+                .get(key)
+                .map(|entry| (entry.0.clone(), Some(entry.1.to_string()))))
+        }
+
+        /// Like a HEAD request, this does not count as a read.
+        async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+            Ok(self.data.get(key).map(|entry| Some(entry.1.to_string())))
+        }
+
+        async fn set_if(
+            &self,
+            key: &str,
+            value: Vec<u8>,
+            expected: &ExpectedVersion,
+        ) -> Result<Option<String>> {
+            let current = self.data.get(key).map(|entry| entry.1.to_string());
+            let matches = match expected {
+                ExpectedVersion::Absent => current.is_none(),
+                ExpectedVersion::Version(version) => current.as_ref() == Some(version),
+                ExpectedVersion::Any => true,
+            };
+            if !matches {
+                return Err(StoreError::Conflict(key.to_owned()));
+            }
+            self.set(key, value).await?;
+            Ok(self.data.get(key).map(|entry| entry.1.to_string()))
+        }
+    }
+
+    async fn check_cache(store: CachedStore, inner: CountingStore) {
+        let v1 = store
+            .set_if("a/data.ysweet", vec![1; 10], &ExpectedVersion::Absent)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_versioned("a/data.ysweet").await.unwrap(),
+            Some((vec![1; 10], v1.clone()))
+        );
+        assert_eq!(inner.reads(), 0);
+
+        // A plain `set` does not report a version, so `b` is only cached once read.
+        // Caching it pushes out `a`, the least recently used entry.
+        store.set("b/data.ysweet", vec![2; 10]).await.unwrap();
+        assert_eq!(store.get("b/data.ysweet").await.unwrap(), Some(vec![2; 10]));
+        assert_eq!(store.get("b/data.ysweet").await.unwrap(), Some(vec![2; 10]));
+        assert_eq!(inner.reads(), 1);
+        assert_eq!(store.get("a/data.ysweet").await.unwrap(), Some(vec![1; 10]));
+        assert_eq!(inner.reads(), 2);
+
+        // Another writer updates `a`, so our cached version is stale and is not served.
+        inner.set("a/data.ysweet", vec![3; 10]).await.unwrap();
+        assert_eq!(store.get("a/data.ysweet").await.unwrap(), Some(vec![3; 10]));
+        assert_eq!(inner.reads(), 3);
+
+        // Our write from before their update conflicts, and drops the entry.
+        let expected = ExpectedVersion::from_store_version(v1);
+        assert!(store
+            .set_if("a/data.ysweet", vec![4; 10], &expected)
+            .await
+            .is_err());
+        assert_eq!(store.get("a/data.ysweet").await.unwrap(), Some(vec![3; 10]));
+        assert_eq!(inner.reads(), 4);
+
+        // Values larger than the cache are passed through.
+        store.set("c/data.ysweet", vec![5; 100]).await.unwrap();
+        assert_eq!(
+            store.get("c/data.ysweet").await.unwrap(),
+            Some(vec![5; 100])
+        );
+        assert_eq!(inner.reads(), 5);
+
+        store.remove("a/data.ysweet").await.unwrap();
+        assert!(!store.exists("a/data.ysweet").await.unwrap());
+        assert_eq!(store.get("a/data.ysweet").await.unwrap(), None);
+
+        // Another writer removes `b` while it is cached.
+        assert_eq!(store.get("b/data.ysweet").await.unwrap(), Some(vec![2; 10]));
+        inner.remove("b/data.ysweet").await.unwrap();
+        assert!(!store.exists("b/data.ysweet").await.unwrap());
+        assert_eq!(store.get("b/data.ysweet").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_cache() {
+        let inner = CountingStore::default();
+        let store = CachedStore::in_memory(Box::new(inner.clone()), 15);
+        check_cache(store, inner).await;
+    }
+
+    #[tokio::test]
+    async fn test_on_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-cache-{}", nanoid::nanoid!()));
+        let inner = CountingStore::default();
+        let store = CachedStore::on_disk(Box::new(inner.clone()), dir.clone(), 15).unwrap();
+        check_cache(store, inner).await;
+
+        // Evicted and invalidated entries leave no files behind.
+        assert_eq!(read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_on_disk_cache_under_encryption() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-cache-{}", nanoid::nanoid!()));
+        let inner = CountingStore::default();
+        let cache = CachedStore::on_disk(Box::new(inner.clone()), dir.clone(), 1024).unwrap();
+        let keys = EncryptionKeys::parse("key1:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        let store = EncryptedStore::new(Box::new(cache), keys.unwrap());
+
+        let plaintext = b"confidential document contents".to_vec();
+        store
+            .set_if("a/data.ysweet", plaintext.clone(), &ExpectedVersion::Absent)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("a/data.ysweet").await.unwrap(),
+            Some(plaintext.clone())
+        );
+        assert_eq!(inner.reads(), 0);
+
+        let files: Vec<_> = read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        let cached = std::fs::read(&files[0]).unwrap();
+        assert!(!cached
+            .windows(plaintext.len())
+            .any(|window| window == plaintext));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,18 @@
 use async_trait::async_trait;
+use sha2::{Digest, Sha256};
 use std::{
-    fs::{create_dir_all, remove_file},
-    path::PathBuf,
+    ffi::OsString,
+    fs::{create_dir_all, hard_link, read_dir, remove_file, rename, File},
+    io::{ErrorKind, Write},
+    path::{Component, Path, PathBuf},
 };
-use y_sweet_core::store::{Result, Store, StoreError};
+use y_sweet_core::store::{ExpectedVersion, Result, Store, StoreError, PREVIOUS_VERSION_SUFFIX};
+
+/// Writes to the files in a directory are serialized by locking this file in it.
+const LOCK_FILE_NAME: &str = ".lock";
+/// Older versions locked a file with this suffix next to each object.
+const LEGACY_LOCK_SUFFIX: &str = ".lock";
+const TEMP_INFIX: &str = ".tmp-";
 
 pub struct FileSystemStore {
     base_path: PathBuf,
@@ -14,6 +23,22 @@ impl FileSystemStore {
         create_dir_all(base_path.clone())?;
         Ok(Self { base_path })
     }
+
+    /// The path of the file that holds `key`. Keys that would resolve to a path outside
+    /// of the base directory, such as ones with `..` segments, are refused.
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(StoreError::NotAuthorized(format!(
+                "Key {} is outside of the store directory.",
+                key
+            )));
+        }
+        Ok(self.base_path.join(relative))
+    }
 }
 
 #[async_trait]
@@ -23,7 +48,7 @@ impl Store for FileSystemStore {
     }
 
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        let path = self.base_path.join(key);
+        let path = self.path(key)?;
         let contents = std::fs::read(path);
         match contents {
             Ok(contents) => Ok(Some(contents)),
@@ -33,23 +58,172 @@ impl Store for FileSystemStore {
     }
 
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
-        let path = self.base_path.join(key);
-        create_dir_all(path.parent().expect("Bad parent"))
-            .map_err(|_| StoreError::NotAuthorized("Error creating directories".to_string()))?;
-        std::fs::write(path, value)
-            .map_err(|_| StoreError::NotAuthorized("Error writing file.".to_string()))?;
-        Ok(())
+        let path = self.path(key)?;
+        let _lock = lock(&path)?;
+        replace(&path, &value)
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        Ok(self.get(key).await?.map(|contents| {
+            let version = content_version(&contents);
+            (contents, Some(version))
+        }))
+    }
+
+    /// The version of a file is the SHA-256 hash of its contents. The check and the
+    /// write happen while holding an exclusive lock on the lock file of the target's
+    /// directory, so they are atomic with respect to other writers using this store.
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        let path = self.path(key)?;
+        let _lock = lock(&path)?;
+
+        let current = match std::fs::read(&path) {
+            Ok(contents) => Some(content_version(&contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(StoreError::ConnectionError(e.to_string())),
+        };
+        let matches = match expected {
+            ExpectedVersion::Absent => current.is_none(),
+            ExpectedVersion::Version(version) => current.as_ref() == Some(version),
+            ExpectedVersion::Any => true,
+        };
+        if !matches {
+            return Err(StoreError::Conflict(format!(
+                "Expected {:?}, found version {:?}.",
+                expected, current
+            )));
+        }
+
+        replace(&path, &value)?;
+        Ok(Some(content_version(&value)))
     }
 
     async fn remove(&self, key: &str) -> Result<()> {
-        let path = self.base_path.join(key);
-        remove_file(path)
+        let path = self.path(key)?;
+        remove_file(&path)
             .map_err(|_| StoreError::NotAuthorized("Error removing file.".to_string()))?;
+        let _ = remove_file(with_suffix(&path, PREVIOUS_VERSION_SUFFIX));
+        let _ = remove_file(with_suffix(&path, LEGACY_LOCK_SUFFIX));
         Ok(())
     }
 
     async fn exists(&self, key: &str) -> Result<bool> {
-        let path = self.base_path.join(key);
+        let path = self.path(key)?;
         Ok(path.exists())
     }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        collect_keys(&self.base_path, "", &mut keys)
+            .map_err(|e| StoreError::ConnectionError(format!("Error listing files. {}", e)))?;
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Adds the keys of the files under `dir` to `keys`, prefixed with `key_prefix`.
+/// Lock files, including the ones older versions left next to each object, and
+/// temporary files of unfinished writes are not objects, so they are skipped.
+fn collect_keys(dir: &Path, key_prefix: &str, keys: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let key = format!("{}{}", key_prefix, name);
+        if entry.file_type()?.is_dir() {
+            collect_keys(&entry.path(), &format!("{}/", key), keys)?;
+        } else if !name.ends_with(LEGACY_LOCK_SUFFIX) && !name.contains(TEMP_INFIX) {
+            keys.push(key);
+        }
+    }
+    Ok(())
+}
+
+/// Creates the parent directories of `path` and takes an exclusive lock on the lock
+/// file in its directory. The lock is released when the returned file is dropped. The
+/// lock file is kept, since removing it could let a writer that is waiting for the
+/// lock and a new writer that creates a new lock file both go ahead.
+fn lock(path: &Path) -> Result<File> {
+    let dir = path.parent().expect("Bad parent");
+    create_dir_all(dir)
+        .map_err(|_| StoreError::NotAuthorized("Error creating directories".to_string()))?;
+    let lock_file = File::create(dir.join(LOCK_FILE_NAME))
+        .map_err(|e| StoreError::NotAuthorized(format!("Error creating lock file. {}", e)))?;
+    lock_file
+        .lock()
+        .map_err(|e| StoreError::ConnectionError(format!("Error locking file. {}", e)))?;
+    Ok(lock_file)
+}
+
+/// Replaces the file atomically: the new contents are written to a temporary file
+/// in the same directory and renamed over the target, so a crash leaves either the
+/// old or the new contents. The old contents are kept as a hard link under
+/// `PREVIOUS_VERSION_SUFFIX`.
+fn replace(path: &Path, value: &[u8]) -> Result<()> {
+    let temp_path = with_suffix(path, &format!("{}{}", TEMP_INFIX, nanoid::nanoid!()));
+    if let Err(e) = write_synced(&temp_path, value) {
+        let _ = remove_file(&temp_path);
+        return Err(StoreError::NotAuthorized(format!(
+            "Error writing file. {}",
+            e
+        )));
+    }
+
+    let previous_path = with_suffix(path, PREVIOUS_VERSION_SUFFIX);
+    match remove_file(&previous_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => tracing::warn!(?e, "Error removing previous version"),
+    }
+    match hard_link(path, &previous_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => tracing::warn!(?e, "Error keeping previous version"),
+    }
+
+    if let Err(e) = rename(&temp_path, path) {
+        let _ = remove_file(&temp_path);
+        return Err(StoreError::NotAuthorized(format!(
+            "Error renaming file. {}",
+            e
+        )));
+    }
+    sync_dir(path.parent().expect("Bad parent"))
+        .map_err(|e| StoreError::NotAuthorized(format!("Error syncing directory. {}", e)))?;
+    Ok(())
+}
+
+fn content_version(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = path.into();
+    path.push(suffix);
+    path.into()
+}
+
+fn write_synced(path: &Path, value: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(value)?;
+    file.sync_all()
+}
+
+/// Makes a rename within `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened as files on Windows, so this is a no-op there.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
 }
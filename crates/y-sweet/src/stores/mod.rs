@@ -1 +1,4 @@
+pub mod cache;
 pub mod filesystem;
+pub mod postgres;
+pub mod sqlite;
@@ -0,0 +1,374 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use y_sweet_core::store::{ExpectedVersion, Result, Store, StoreError};
+
+/// Stores every object as a row of a single SQLite database file.
+///
+/// Each write is a single statement and therefore its own transaction, so a crash
+/// mid-write leaves either the old or the new value in place, never a partial one.
+///
+/// Every row has a version, a counter that each write increments. Conditional writes
+/// check it in the same statement that writes the row, so they are atomic even when
+/// several processes share the database file.
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn new(path: &Path) -> std::result::Result<Self, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        // WAL lets readers proceed while a write is in progress, and only needs one
+        // fsync per transaction.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs a query against the connection on the blocking thread pool, so that
+    /// SQLite's disk I/O does not stall the async runtime.
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap();
+            f(&connection)
+        })
+        .await
+        .map_err(|e| StoreError::ConnectionError(e.to_string()))?
+        .map_err(|e| StoreError::ConnectionError(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn init(&self) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute(
+                "CREATE TABLE IF NOT EXISTS objects (
+                    key TEXT PRIMARY KEY NOT NULL,
+                    value BLOB NOT NULL,
+                    version INTEGER NOT NULL DEFAULT 0
+                ) WITHOUT ROWID",
+                [],
+            )?;
+            // Databases created before rows were versioned lack the column.
+            let has_version: bool = connection.query_row(
+                "SELECT EXISTS (
+                    SELECT 1 FROM pragma_table_info('objects') WHERE name = 'version'
+                )",
+                [],
+                |row| row.get(0),
+            )?;
+            if !has_version {
+                connection.execute(
+                    "ALTER TABLE objects ADD COLUMN version INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.to_owned();
+        self.with_connection(move |connection| {
+            connection
+                .prepare_cached("SELECT value FROM objects WHERE key = ?1")?
+                .query_row(params![key], |row| row.get(0))
+                .optional()
+        })
+        .await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set_if(key, value, &ExpectedVersion::Any).await?;
+        Ok(())
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        let key = key.to_owned();
+        self.with_connection(move |connection| {
+            connection
+                .prepare_cached("SELECT value, version FROM objects WHERE key = ?1")?
+                .query_row(params![key], |row| {
+                    Ok((row.get(0)?, Some(row.get::<_, i64>(1)?.to_string())))
+                })
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<Option<String>>> {
+        let key = key.to_owned();
+        self.with_connection(move |connection| {
+            connection
+                .prepare_cached("SELECT version FROM objects WHERE key = ?1")?
+                .query_row(params![key], |row| {
+                    Ok(Some(row.get::<_, i64>(0)?.to_string()))
+                })
+                .optional()
+        })
+        .await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: &ExpectedVersion,
+    ) -> Result<Option<String>> {
+        let key = key.to_owned();
+        let version: Option<i64> = match expected {
+            ExpectedVersion::Any => {
+                self.with_connection(move |connection| {
+                    connection
+                        .prepare_cached(
+                            "INSERT INTO objects (key, value, version) VALUES (?1, ?2, 1)
+                            ON CONFLICT (key) DO UPDATE
+                            SET value = excluded.value, version = objects.version + 1
+                            RETURNING version",
+                        )?
+                        .query_row(params![key, value], |row| row.get(0))
+                        .optional()
+                })
+                .await?
+            }
+            ExpectedVersion::Absent => {
+                self.with_connection(move |connection| {
+                    connection
+                        .prepare_cached(
+                            "INSERT INTO objects (key, value, version) VALUES (?1, ?2, 1)
+                            ON CONFLICT (key) DO NOTHING
+                            RETURNING version",
+                        )?
+                        .query_row(params![key, value], |row| row.get(0))
+                        .optional()
+                })
+                .await?
+            }
+            ExpectedVersion::Version(version) => match version.parse::<i64>() {
+                Ok(version) => {
+                    self.with_connection(move |connection| {
+                        connection
+                            .prepare_cached(
+                                "UPDATE objects SET value = ?2, version = version + 1
+                                WHERE key = ?1 AND version = ?3
+                                RETURNING version",
+                            )?
+                            .query_row(params![key, value, version], |row| row.get(0))
+                            .optional()
+                    })
+                    .await?
+                }
+                // Not a version this store handed out, so it cannot match.
+                Err(_) => None,
+            },
+        };
+
+        match version {
+            Some(version) => Ok(Some(version.to_string())),
+            None => Err(StoreError::Conflict(format!(
+                "Expected {:?}, but the object is in a different state.",
+                expected
+            ))),
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        let key = key.to_owned();
+        self.with_connection(move |connection| {
+            connection
+                .prepare_cached("DELETE FROM objects WHERE key = ?1")?
+                .execute(params![key])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let key = key.to_owned();
+        self.with_connection(move |connection| {
+            connection
+                .prepare_cached("SELECT EXISTS (SELECT 1 FROM objects WHERE key = ?1)")?
+                .query_row(params![key], |row| row.get(0))
+        })
+        .await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // Matching on a key range rather than with LIKE lets SQLite use the primary key
+        // index. U+10FFFF is the largest code point, so under the default binary
+        // collation every key that starts with `prefix` sorts below the upper bound.
+        let lower = prefix.to_owned();
+        let upper = format!("{prefix}\u{10FFFF}");
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT key FROM objects WHERE key >= ?1 AND key < ?2 ORDER BY key",
+            )?;
+            let keys = statement
+                .query_map(params![lower, upper], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let lower = prefix.to_owned();
+        let upper = format!("{prefix}\u{10FFFF}");
+        // Every key is greater than the empty string.
+        let start_after = start_after.unwrap_or("").to_owned();
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT key FROM objects WHERE key >= ?1 AND key < ?2 AND key > ?3
+                ORDER BY key LIMIT ?4",
+            )?;
+            let keys = statement
+                .query_map(params![lower, upper, start_after, limit], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(keys)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let store = SqliteStore::new(Path::new(":memory:")).unwrap();
+        store.init().await.unwrap();
+
+        assert!(!store.exists("doc1/data.ysweet").await.unwrap());
+        assert_eq!(store.get("doc1/data.ysweet").await.unwrap(), None);
+
+        store.set("doc1/data.ysweet", vec![1, 2, 3]).await.unwrap();
+        store.set("doc2/data.ysweet", vec![4]).await.unwrap();
+        store.set("doc1/data.ysweet", vec![5, 6]).await.unwrap();
+
+        assert!(store.exists("doc1/data.ysweet").await.unwrap());
+        assert_eq!(
+            store.get("doc1/data.ysweet").await.unwrap(),
+            Some(vec![5, 6])
+        );
+        assert_eq!(
+            store.list("doc").await.unwrap(),
+            vec!["doc1/data.ysweet", "doc2/data.ysweet"]
+        );
+        assert_eq!(store.list("doc2/").await.unwrap(), vec!["doc2/data.ysweet"]);
+        assert_eq!(
+            store.list_page("doc", None, 1).await.unwrap(),
+            vec!["doc1/data.ysweet"]
+        );
+        assert_eq!(
+            store
+                .list_page("doc", Some("doc1/data.ysweet"), 10)
+                .await
+                .unwrap(),
+            vec!["doc2/data.ysweet"]
+        );
+
+        store.remove("doc1/data.ysweet").await.unwrap();
+        assert!(!store.exists("doc1/data.ysweet").await.unwrap());
+        assert_eq!(store.list("").await.unwrap(), vec!["doc2/data.ysweet"]);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_set_if() {
+        let store = SqliteStore::new(Path::new(":memory:")).unwrap();
+        store.init().await.unwrap();
+        let key = "doc1/data.ysweet";
+
+        let version = store
+            .set_if(key, vec![1], &ExpectedVersion::Absent)
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.set_if(key, vec![2], &ExpectedVersion::Absent).await,
+            Err(StoreError::Conflict(_))
+        ));
+        assert_eq!(
+            store.get_versioned(key).await.unwrap(),
+            Some((vec![1], version.clone()))
+        );
+        assert_eq!(store.get_version(key).await.unwrap(), Some(version.clone()));
+        assert_eq!(store.get_version("doc2/data.ysweet").await.unwrap(), None);
+
+        let expected = ExpectedVersion::from_store_version(version);
+        let version = store.set_if(key, vec![3], &expected).await.unwrap();
+        // Another writer got there first with the same expectation.
+        assert!(matches!(
+            store.set_if(key, vec![4], &expected).await,
+            Err(StoreError::Conflict(_))
+        ));
+        assert!(matches!(
+            store
+                .set_if(key, vec![4], &ExpectedVersion::Version("x".to_string()))
+                .await,
+            Err(StoreError::Conflict(_))
+        ));
+
+        // Unconditional writes also move the version on.
+        store.set(key, vec![5]).await.unwrap();
+        let (value, new_version) = store.get_versioned(key).await.unwrap().unwrap();
+        assert_eq!(value, vec![5]);
+        assert_ne!(new_version, version);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_adds_version_column() {
+        let path = std::env::temp_dir().join(format!("y-sweet-sqlite-{}.db", nanoid::nanoid!()));
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE objects (
+                    key TEXT PRIMARY KEY NOT NULL,
+                    value BLOB NOT NULL
+                ) WITHOUT ROWID;
+                INSERT INTO objects (key, value) VALUES ('doc1/data.ysweet', x'01');",
+            )
+            .unwrap();
+        drop(connection);
+
+        let store = SqliteStore::new(&path).unwrap();
+        store.init().await.unwrap();
+        // Running init again must not try to add the column twice.
+        store.init().await.unwrap();
+
+        let (value, version) = store
+            .get_versioned("doc1/data.ysweet")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, vec![1]);
+        store
+            .set_if(
+                "doc1/data.ysweet",
+                vec![2],
+                &ExpectedVersion::from_store_version(version),
+            )
+            .await
+            .unwrap();
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -38,9 +38,16 @@ export type AuthDocRequest = {
   /** The authorization level to use for the document. Defaults to 'full'. */
   authorization?: Authorization
 
-  /** A user ID to associate with the token. Not currently used. */
+  /** A user ID to associate with the token, recorded in the audit log if the server runs with `--doc-token-user-ids`. */
   userId?: string
 
   /** The number of seconds the token should be valid for. */
   validForSeconds?: number
+
+  /**
+   * Make the token valid for every document whose ID matches this pattern, such as
+   * `ws123-*`, where `*` matches any run of characters. The pattern must match the
+   * requested document.
+   */
+  docPattern?: string
 }